@@ -0,0 +1,122 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use crate::{
+    BoxedIncludeProviderError, IncludeKind, IncludeProvider, ResolvedInclude, ResolvedIncludePath,
+};
+
+/// `IncludeProvider` wrapper memoizing the results of another provider,
+/// so that processing many roots sharing the same headers only reads each header once.
+///
+/// A content hash (64-bit XXH3) is kept for every file read, e.g. for keying caches
+/// of compiled shaders. Errors are not cached.
+///
+/// The cache is never invalidated on its own; use [`invalidate`](Self::invalidate)
+/// or [`clear`](Self::clear) when files change.
+pub struct CachingIncludeProvider<P: IncludeProvider> {
+    inner: P,
+    resolved: RefCell<ResolveCache<P::IncludeContext>>,
+    files: HashMap<ResolvedIncludePath, CachedFile>,
+}
+
+/// Results of `resolve_path`, keyed by its arguments
+type ResolveCache<IncludeContext> = HashMap<
+    (
+        String,
+        IncludeKind,
+        Option<ResolvedIncludePath>,
+        IncludeContext,
+    ),
+    ResolvedInclude<IncludeContext>,
+>;
+
+struct CachedFile {
+    source: String,
+    hash: u64,
+}
+
+impl<P> CachingIncludeProvider<P>
+where
+    P: IncludeProvider,
+    P::IncludeContext: Clone + Eq + Hash,
+{
+    pub fn new(inner: P) -> Self {
+        Self {
+            inner,
+            resolved: RefCell::new(HashMap::new()),
+            files: HashMap::new(),
+        }
+    }
+
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+
+    /// Hash of the contents of `path`, if it has been read through this provider.
+    pub fn content_hash(&self, path: &ResolvedIncludePath) -> Option<u64> {
+        self.files.get(path).map(|file| file.hash)
+    }
+
+    /// Forget the contents of `path`. Path resolutions are kept.
+    pub fn invalidate(&mut self, path: &ResolvedIncludePath) {
+        self.files.remove(path);
+    }
+
+    /// Forget all path resolutions and file contents.
+    pub fn clear(&mut self) {
+        self.resolved.get_mut().clear();
+        self.files.clear();
+    }
+}
+
+impl<P> IncludeProvider for CachingIncludeProvider<P>
+where
+    P: IncludeProvider,
+    P::IncludeContext: Clone + Eq + Hash,
+{
+    type IncludeContext = P::IncludeContext;
+
+    fn resolve_path(
+        &self,
+        path: &str,
+        kind: IncludeKind,
+        includer: Option<&ResolvedIncludePath>,
+        context: &Self::IncludeContext,
+    ) -> Result<ResolvedInclude<Self::IncludeContext>, BoxedIncludeProviderError> {
+        let key = (path.to_owned(), kind, includer.cloned(), context.clone());
+
+        if let Some(resolved) = self.resolved.borrow().get(&key) {
+            return Ok(resolved.clone());
+        }
+
+        let resolved = self.inner.resolve_path(path, kind, includer, context)?;
+        self.resolved.borrow_mut().insert(key, resolved.clone());
+
+        Ok(resolved)
+    }
+
+    fn get_include(
+        &mut self,
+        path: &ResolvedIncludePath,
+    ) -> Result<String, BoxedIncludeProviderError> {
+        if let Some(file) = self.files.get(path) {
+            return Ok(file.source.clone());
+        }
+
+        let source = self.inner.get_include(path)?;
+        self.files.insert(
+            path.clone(),
+            CachedFile {
+                source: source.clone(),
+                hash: xxhash_rust::xxh3::xxh3_64(source.as_bytes()),
+            },
+        );
+
+        Ok(source)
+    }
+}
@@ -0,0 +1,63 @@
+use crate::pp_token::{is_ident_continue, is_ident_start};
+
+/// A macro registered via `#define`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct MacroDefinition {
+    /// Parameter names of a function-like macro; `None` for object-like macros
+    pub params: Option<Vec<String>>,
+
+    /// Replacement list, with surrounding whitespace trimmed
+    pub body: String,
+}
+
+/// Splits a leading identifier off `s`, skipping whitespace before it.
+pub(crate) fn split_ident(s: &str) -> Option<(&str, &str)> {
+    let s = s.trim_start();
+
+    if !s.starts_with(is_ident_start) {
+        return None;
+    }
+
+    let end = s.find(|c: char| !is_ident_continue(c)).unwrap_or(s.len());
+
+    Some((&s[..end], &s[end..]))
+}
+
+/// Parses the part of a `#define` directive following the directive name.
+pub(crate) fn parse_define(s: &str) -> Option<(String, MacroDefinition)> {
+    let (name, rest) = split_ident(s)?;
+
+    // Function-like macros have the parameter list immediately following the name.
+    let (params, body) = if let Some(rest) = rest.strip_prefix('(') {
+        let end = rest.find(')')?;
+        let param_list = rest[..end].trim();
+
+        let params = if param_list.is_empty() {
+            Vec::new()
+        } else {
+            param_list
+                .split(',')
+                .map(|p| {
+                    let p = p.trim();
+                    if p == "..." || split_ident(p).is_some_and(|(_, rest)| rest.is_empty()) {
+                        Some(p.to_string())
+                    } else {
+                        None
+                    }
+                })
+                .collect::<Option<Vec<_>>>()?
+        };
+
+        (Some(params), &rest[end + 1..])
+    } else {
+        (None, rest)
+    };
+
+    Some((
+        name.to_string(),
+        MacroDefinition {
+            params,
+            body: body.trim().to_string(),
+        },
+    ))
+}
@@ -0,0 +1,27 @@
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+    Info,
+}
+
+/// Single message from a shader compiler's log, with its location remapped to the original file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+
+    /// File the message refers to, as named by `SourceChunk::file`.
+    /// `None` if the compiler reported a location which doesn't correspond to any chunk.
+    pub file: Option<String>,
+
+    /// One-based line in `file`, as reported by the compiler after remapping.
+    /// If `file` is `None`, the line as reported by the compiler, or `None` if that number
+    /// doesn't fit in a `usize`.
+    pub line: Option<usize>,
+
+    /// Column in the line, for compilers which report one
+    pub column: Option<usize>,
+
+    /// Text of the message, without the location and severity
+    pub message: String,
+}
@@ -1,18 +1,52 @@
 pub type BoxedIncludeProviderError = Box<dyn std::error::Error + Send + Sync + 'static>;
 
+/// Location within one of the files being processed when an error occurred
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IncludeStackEntry {
+    /// Resolved path of the file
+    pub file: String,
+
+    /// Line in `file`: that of the `#include` leading to the next entry,
+    /// or of the error itself for the last one
+    pub line: usize,
+}
+
+impl std::fmt::Display for IncludeStackEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+fn format_include_stack(include_stack: &[IncludeStackEntry]) -> String {
+    if include_stack.is_empty() {
+        String::new()
+    } else {
+        let entries: Vec<String> = include_stack.iter().map(|e| e.to_string()).collect();
+        format!("; include stack: {}", entries.join(" -> "))
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum PrepperError {
     /// Any error reported by the user-supplied `IncludeProvider`
-    #[error("include provider error: \"{cause:?}\" when trying to include {file:?}")]
+    #[error(
+        "include provider error: \"{cause:?}\" when trying to include {file:?}{}",
+        format_include_stack(include_stack)
+    )]
     IncludeProviderError {
         file: String,
         cause: BoxedIncludeProviderError,
+        include_stack: Vec<IncludeStackEntry>,
     },
 
     /// Recursively included file, along with information about where it was encountered
-    #[error("file {file:?} is recursively included; triggered in {from:?} ({from_line:?})")]
+    #[error(
+        "file {file:?} is recursively included; triggered in {from:?} ({from_line:?}); cycle: {}{}",
+        cycle.join(" -> "),
+        format_include_stack(include_stack)
+    )]
     RecursiveInclude {
-        /// File which was included recursively
+        /// Resolved path of the file which was included recursively
         file: String,
 
         /// File which included the recursively included one
@@ -20,9 +54,66 @@ pub enum PrepperError {
 
         /// Line in the `from` file on which the include happened
         from_line: usize,
+
+        /// Resolved paths of the files forming the cycle, starting and ending with `file`
+        cycle: Vec<String>,
+
+        include_stack: Vec<IncludeStackEntry>,
     },
 
     /// Error parsing an include directive
-    #[error("parse error: {file:?} ({line:?})")]
-    ParseError { file: String, line: usize },
+    #[error(
+        "parse error: {file:?} ({line:?}:{column:?}){}",
+        format_include_stack(include_stack)
+    )]
+    ParseError {
+        file: String,
+        line: usize,
+
+        /// One-based column of the offending character, counted in characters
+        column: usize,
+
+        /// Offset of the offending character from the start of `file`, in bytes
+        byte_offset: usize,
+
+        include_stack: Vec<IncludeStackEntry>,
+    },
+
+    /// Malformed or unbalanced preprocessor directive, e.g. an `#endif` without a matching `#if`
+    #[error(
+        "{message}: {file:?} ({line:?}){}",
+        format_include_stack(include_stack)
+    )]
+    DirectiveError {
+        file: String,
+        line: usize,
+        message: String,
+        include_stack: Vec<IncludeStackEntry>,
+    },
+
+    /// Error expanding a macro invocation, e.g. one with the wrong number of arguments
+    #[error(
+        "macro expansion error: {message}: {file:?} ({line:?}){}",
+        format_include_stack(include_stack)
+    )]
+    MacroError {
+        file: String,
+        line: usize,
+        message: String,
+        include_stack: Vec<IncludeStackEntry>,
+    },
+}
+
+impl PrepperError {
+    /// Files being processed when the error occurred, from the root file down to the one
+    /// containing the error. Empty if the root file itself could not be read.
+    pub fn include_stack(&self) -> &[IncludeStackEntry] {
+        match self {
+            PrepperError::IncludeProviderError { include_stack, .. }
+            | PrepperError::RecursiveInclude { include_stack, .. }
+            | PrepperError::ParseError { include_stack, .. }
+            | PrepperError::DirectiveError { include_stack, .. }
+            | PrepperError::MacroError { include_stack, .. } => include_stack,
+        }
+    }
 }
@@ -0,0 +1,271 @@
+//! Evaluation of `#if` and `#elif` controlling expressions.
+
+use std::collections::HashMap;
+
+use crate::defines::MacroDefinition;
+use crate::pp_token::{tokenize, Token, TokenKind};
+
+pub(crate) fn evaluate(
+    expr: &str,
+    defines: &HashMap<String, MacroDefinition>,
+) -> Result<i64, String> {
+    let tokens = resolve_defined(tokenize(expr), defines)?;
+
+    let tokens = crate::macros::expand_tokens(tokens, defines)?;
+    let tokens = replace_identifiers(tokens);
+
+    let mut parser = Parser {
+        tokens: &tokens,
+        pos: 0,
+        unevaluated: 0,
+        depth: 0,
+    };
+
+    let value = parser.parse_conditional()?;
+
+    match parser.peek() {
+        None => Ok(value),
+        Some(t) => Err(format!("unexpected token {:?} in expression", t.text)),
+    }
+}
+
+/// Replaces `defined X` and `defined(X)` with `1` or `0`, and drops whitespace.
+fn resolve_defined(
+    tokens: Vec<Token>,
+    defines: &HashMap<String, MacroDefinition>,
+) -> Result<Vec<Token>, String> {
+    let mut result = Vec::new();
+    let mut it = tokens
+        .into_iter()
+        .filter(|t| t.kind != TokenKind::Whitespace);
+
+    while let Some(t) = it.next() {
+        if t.kind != TokenKind::Ident || t.text != "defined" {
+            result.push(t);
+            continue;
+        }
+
+        let name = match it.next() {
+            Some(t) if t.kind == TokenKind::Ident => t,
+            Some(t) if t.is_punct("(") => match (it.next(), it.next()) {
+                (Some(name), Some(close))
+                    if name.kind == TokenKind::Ident && close.is_punct(")") =>
+                {
+                    name
+                }
+                _ => return Err("malformed `defined` operator".to_string()),
+            },
+            _ => return Err("`defined` without a macro name".to_string()),
+        };
+
+        let value = if defines.contains_key(&name.text) {
+            "1"
+        } else {
+            "0"
+        };
+        result.push(Token::new(TokenKind::Number, value));
+    }
+
+    Ok(result)
+}
+
+/// Replaces the identifiers left after macro expansion with `0`, and drops whitespace.
+fn replace_identifiers(tokens: Vec<Token>) -> Vec<Token> {
+    tokens
+        .into_iter()
+        .filter(|t| t.kind != TokenKind::Whitespace)
+        .map(|t| {
+            if t.kind == TokenKind::Ident {
+                Token::new(TokenKind::Number, "0")
+            } else {
+                t
+            }
+        })
+        .collect()
+}
+
+fn parse_number(s: &str) -> Option<i64> {
+    let s = s.trim_end_matches(['u', 'U', 'l', 'L']);
+
+    if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        u64::from_str_radix(hex, 16).ok().map(|v| v as i64)
+    } else if s.len() > 1 && s.starts_with('0') {
+        u64::from_str_radix(&s[1..], 8).ok().map(|v| v as i64)
+    } else {
+        s.parse::<u64>().ok().map(|v| v as i64)
+    }
+}
+
+fn binary_precedence(op: &str) -> Option<u32> {
+    Some(match op {
+        "*" | "/" | "%" => 10,
+        "+" | "-" => 9,
+        "<<" | ">>" => 8,
+        "<" | ">" | "<=" | ">=" => 7,
+        "==" | "!=" => 6,
+        "&" => 5,
+        "^" => 4,
+        "|" => 3,
+        "&&" => 2,
+        "||" => 1,
+        _ => return None,
+    })
+}
+
+/// Nesting of parentheses and operators beyond which expressions are rejected,
+/// rather than overflowing the stack
+const MAX_DEPTH: u32 = 256;
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+
+    // Non-zero while parsing an operand which short-circuiting skips;
+    // errors such as division by zero are not reported there.
+    unevaluated: u32,
+
+    // Number of `parse_nested` calls in progress
+    depth: u32,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<&'a Token> {
+        let t = self.tokens.get(self.pos);
+        self.pos += 1;
+        t
+    }
+
+    fn expect_punct(&mut self, p: &str) -> Result<(), String> {
+        match self.next() {
+            Some(t) if t.is_punct(p) => Ok(()),
+            Some(t) => Err(format!("expected {:?}, found {:?}", p, t.text)),
+            None => Err(format!("expected {:?} at end of expression", p)),
+        }
+    }
+
+    fn parse_nested(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> Result<i64, String>,
+    ) -> Result<i64, String> {
+        if self.depth >= MAX_DEPTH {
+            return Err("expression nested too deeply".to_string());
+        }
+
+        self.depth += 1;
+        let res = parse(self);
+        self.depth -= 1;
+        res
+    }
+
+    fn parse_operand(&mut self, skip: bool) -> Result<i64, String> {
+        if skip {
+            self.unevaluated += 1;
+        }
+        let res = self.parse_nested(Self::parse_conditional);
+        if skip {
+            self.unevaluated -= 1;
+        }
+        res
+    }
+
+    fn parse_conditional(&mut self) -> Result<i64, String> {
+        let cond = self.parse_binary(1)?;
+
+        if matches!(self.peek(), Some(t) if t.is_punct("?")) {
+            let _ = self.next();
+            let a = self.parse_operand(cond == 0)?;
+            self.expect_punct(":")?;
+            let b = self.parse_operand(cond != 0)?;
+            Ok(if cond != 0 { a } else { b })
+        } else {
+            Ok(cond)
+        }
+    }
+
+    fn parse_binary(&mut self, min_precedence: u32) -> Result<i64, String> {
+        let mut lhs = self.parse_unary()?;
+
+        while let Some(op) = self.peek() {
+            let precedence = match binary_precedence(&op.text) {
+                Some(p) if op.kind == TokenKind::Punct && p >= min_precedence => p,
+                _ => break,
+            };
+            let _ = self.next();
+
+            let skip_rhs = match op.text.as_str() {
+                "&&" => lhs == 0,
+                "||" => lhs != 0,
+                _ => false,
+            };
+
+            if skip_rhs {
+                self.unevaluated += 1;
+            }
+            let rhs = self.parse_binary(precedence + 1);
+            if skip_rhs {
+                self.unevaluated -= 1;
+            }
+
+            lhs = self.apply_binary(&op.text, lhs, rhs?)?;
+        }
+
+        Ok(lhs)
+    }
+
+    fn apply_binary(&self, op: &str, a: i64, b: i64) -> Result<i64, String> {
+        Ok(match op {
+            "*" => a.wrapping_mul(b),
+            "/" | "%" if b == 0 => {
+                if self.unevaluated > 0 {
+                    0
+                } else {
+                    return Err("division by zero in expression".to_string());
+                }
+            }
+            "/" => a.wrapping_div(b),
+            "%" => a.wrapping_rem(b),
+            "+" => a.wrapping_add(b),
+            "-" => a.wrapping_sub(b),
+            "<<" => a.wrapping_shl(b as u32),
+            ">>" => a.wrapping_shr(b as u32),
+            "<" => (a < b) as i64,
+            ">" => (a > b) as i64,
+            "<=" => (a <= b) as i64,
+            ">=" => (a >= b) as i64,
+            "==" => (a == b) as i64,
+            "!=" => (a != b) as i64,
+            "&" => a & b,
+            "^" => a ^ b,
+            "|" => a | b,
+            "&&" => (a != 0 && b != 0) as i64,
+            "||" => (a != 0 || b != 0) as i64,
+            _ => unreachable!(),
+        })
+    }
+
+    fn parse_unary(&mut self) -> Result<i64, String> {
+        let t = self
+            .next()
+            .ok_or_else(|| "unexpected end of expression".to_string())?;
+
+        match (t.kind, t.text.as_str()) {
+            (TokenKind::Number, text) => {
+                parse_number(text).ok_or_else(|| format!("invalid number {:?}", text))
+            }
+            (TokenKind::Punct, "(") => {
+                let value = self.parse_nested(Self::parse_conditional)?;
+                self.expect_punct(")")?;
+                Ok(value)
+            }
+            (TokenKind::Punct, "!") => Ok((self.parse_nested(Self::parse_unary)? == 0) as i64),
+            (TokenKind::Punct, "~") => Ok(!self.parse_nested(Self::parse_unary)?),
+            (TokenKind::Punct, "-") => Ok(self.parse_nested(Self::parse_unary)?.wrapping_neg()),
+            (TokenKind::Punct, "+") => self.parse_nested(Self::parse_unary),
+            (_, text) => Err(format!("unexpected token {:?} in expression", text)),
+        }
+    }
+}
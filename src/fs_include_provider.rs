@@ -0,0 +1,170 @@
+use std::path::{Component, Path, PathBuf};
+
+use crate::{
+    BoxedIncludeProviderError, IncludeKind, IncludeProvider, ResolvedInclude, ResolvedIncludePath,
+};
+
+/// `IncludeProvider` reading files from the filesystem.
+///
+/// Quoted includes are first looked up relative to the directory of the including file,
+/// and then in each of the include directories, in order. Angle-bracket includes
+/// are only looked up in the include directories.
+///
+/// The `IncludeContext` is the directory containing the file; pass the directory against which
+/// the root file path should be resolved (typically an empty path) to `process_file`.
+///
+/// Resolved paths are normalized lexically (without consulting the filesystem),
+/// so that e.g. `a/../b.glsl` and `b.glsl` are recognized as the same file.
+pub struct FsIncludeProvider {
+    include_dirs: Vec<PathBuf>,
+}
+
+impl FsIncludeProvider {
+    pub fn new<Dir: Into<PathBuf>>(include_dirs: impl IntoIterator<Item = Dir>) -> Self {
+        Self {
+            include_dirs: include_dirs.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    pub fn include_dirs(&self) -> &[PathBuf] {
+        &self.include_dirs
+    }
+}
+
+impl IncludeProvider for FsIncludeProvider {
+    type IncludeContext = PathBuf;
+
+    fn resolve_path(
+        &self,
+        path: &str,
+        kind: IncludeKind,
+        _includer: Option<&ResolvedIncludePath>,
+        context: &Self::IncludeContext,
+    ) -> Result<ResolvedInclude<Self::IncludeContext>, BoxedIncludeProviderError> {
+        let local_dir = match kind {
+            IncludeKind::Quoted => Some(context),
+            IncludeKind::AngleBracket => None,
+        };
+
+        let (resolved_path, resolved) =
+            find_include(path, local_dir.into_iter().chain(self.include_dirs.iter()))?;
+
+        Ok(ResolvedInclude {
+            resolved_path,
+            context: resolved.parent().map(Path::to_path_buf).unwrap_or_default(),
+        })
+    }
+
+    fn get_include(
+        &mut self,
+        path: &ResolvedIncludePath,
+    ) -> Result<String, BoxedIncludeProviderError> {
+        Ok(std::fs::read_to_string(&path.0)?)
+    }
+}
+
+/// `IncludeProvider` reading files from the filesystem, following the search order of DXC.
+///
+/// Quoted includes are first looked up relative to the directory of the including file,
+/// then relative to the directories of the files which included it, in turn, up to the root file,
+/// and finally in each of the include directories. Angle-bracket includes are only looked up
+/// in the include directories.
+///
+/// The `IncludeContext` is the list of directories containing the file and its includers,
+/// innermost first. Pass the directory against which the root file path should be resolved
+/// (typically `vec![PathBuf::new()]`) to `process_file`.
+pub struct HlslIncludeProvider {
+    include_dirs: Vec<PathBuf>,
+}
+
+impl HlslIncludeProvider {
+    pub fn new<Dir: Into<PathBuf>>(include_dirs: impl IntoIterator<Item = Dir>) -> Self {
+        Self {
+            include_dirs: include_dirs.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    pub fn include_dirs(&self) -> &[PathBuf] {
+        &self.include_dirs
+    }
+}
+
+impl IncludeProvider for HlslIncludeProvider {
+    type IncludeContext = Vec<PathBuf>;
+
+    fn resolve_path(
+        &self,
+        path: &str,
+        kind: IncludeKind,
+        _includer: Option<&ResolvedIncludePath>,
+        context: &Self::IncludeContext,
+    ) -> Result<ResolvedInclude<Self::IncludeContext>, BoxedIncludeProviderError> {
+        let includer_dirs = match kind {
+            IncludeKind::Quoted => context.as_slice(),
+            IncludeKind::AngleBracket => &[],
+        };
+
+        let (resolved_path, resolved) =
+            find_include(path, includer_dirs.iter().chain(self.include_dirs.iter()))?;
+
+        let dir = resolved.parent().map(Path::to_path_buf).unwrap_or_default();
+        Ok(ResolvedInclude {
+            resolved_path,
+            context: std::iter::once(dir)
+                .chain(context.iter().cloned())
+                .collect(),
+        })
+    }
+
+    fn get_include(
+        &mut self,
+        path: &ResolvedIncludePath,
+    ) -> Result<String, BoxedIncludeProviderError> {
+        Ok(std::fs::read_to_string(&path.0)?)
+    }
+}
+
+/// Finds `path` in the first of `dirs` containing it, returning both the resolved path and
+/// the normalized filesystem path.
+fn find_include<'a>(
+    path: &str,
+    dirs: impl Iterator<Item = &'a PathBuf>,
+) -> Result<(ResolvedIncludePath, PathBuf), BoxedIncludeProviderError> {
+    let resolved = dirs
+        .map(|dir| normalize_path(&dir.join(path)))
+        .find(|candidate| candidate.is_file())
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("could not find include file {:?}", path),
+            )
+        })?;
+
+    let resolved_path = resolved
+        .to_str()
+        .ok_or_else(|| format!("include path {:?} is not valid UTF-8", resolved))?
+        .to_owned();
+
+    Ok((ResolvedIncludePath(resolved_path), resolved))
+}
+
+/// Removes `.` components, and `..` ones along with their preceding component where possible.
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut result = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if matches!(result.components().next_back(), Some(Component::Normal(_))) {
+                    result.pop();
+                } else if !result.has_root() {
+                    result.push(component);
+                }
+            }
+            _ => result.push(component),
+        }
+    }
+
+    result
+}
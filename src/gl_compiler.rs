@@ -5,9 +5,14 @@
 //! of those strings when reporting errors. On top of that, the log output format is vendor-specific.
 //!
 //! This module provides the `compile_shader` function, which helps simplify the process:
-//! for each `SourceChunk`, it creates a `String` decorated with a `#line` pragma,
+//! it precedes each `SourceChunk` with a `#line` pragma,
 //! calls the user-provided compiler callback, and then parses its output, mapping
-//! integral source locations to paths used by the `IncludeProvider`.
+//! integral source locations to paths used by the `IncludeProvider`. Besides the rewritten log,
+//! each message is also returned as a structured `Diagnostic`.
+//! Log formats of the common drivers are recognized by [`DEFAULT_LOG_PARSERS`],
+//! and others can be handled by implementing `LogParser`.
+//! Callbacks which can fail, e.g. returning a `Result` from a graphics API wrapper,
+//! can be used via `try_compile_shader`.
 //!
 //! An example implementation using `compile_shader` and the `gl_generator` crate:
 //!
@@ -78,12 +83,187 @@
 //! }
 //! ```
 
+use std::ops::Range;
+
+pub use crate::{Diagnostic, Severity};
+
 /// User-defined output of OpenGL's shader compiler, along with an info log.
 pub struct ShaderCompilerOutput<Artifact> {
     pub artifact: Artifact,
     pub log: Option<String>,
 }
 
+/// Result of `compile_shader`: the compiler's output with locations remapped to the original files.
+pub struct CompiledShader<Artifact> {
+    pub artifact: Artifact,
+
+    /// The compiler's log, with locations rewritten to refer to the original files
+    pub log: Option<String>,
+
+    /// Messages parsed out of the log, in order
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// Location and message recognized in a single line of a shader compiler's log
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogLine {
+    /// Byte range of the location within the line, replaced by the original file and line
+    /// in the remapped log
+    pub location: Range<usize>,
+
+    /// Index of the source string, as reported by the compiler.
+    /// `None` if the number doesn't fit, which leaves the location unmapped.
+    pub source_string: Option<usize>,
+
+    /// Line within the source string, as reported by the compiler.
+    /// `None` if the number doesn't fit, which leaves the location unmapped.
+    pub line: Option<usize>,
+
+    pub column: Option<usize>,
+
+    /// `None` if the line doesn't state a severity, in which case no `Diagnostic` is created
+    pub severity: Option<Severity>,
+
+    /// Byte range of the message text within the line
+    pub message: Range<usize>,
+}
+
+/// Recognizes locations in lines of a particular compiler's log.
+///
+/// `compile_shader` uses [`DEFAULT_LOG_PARSERS`]; others can be supplied via
+/// `compile_shader_with_log_parsers`.
+pub trait LogParser {
+    fn parse_line(&self, line: &str) -> Option<LogLine>;
+}
+
+/// `ERROR: 0:12: message` and `WARNING: 0:12: message`, optionally with a column
+/// (`0:12:5:`). Used by glslang, AMD, Intel on Windows, Apple, Qualcomm Adreno and ANGLE.
+pub struct GlslangStyleLogParser;
+
+impl LogParser for GlslangStyleLogParser {
+    fn parse_line(&self, line: &str) -> Option<LogLine> {
+        lazy_static::lazy_static! {
+            static ref RE: regex::Regex =
+                regex::Regex::new(r"^(ERROR|WARNING|INFO|NOTE):\s*((\d+):(\d+)(?::(\d+))?)").unwrap();
+        }
+
+        let captures = RE.captures(line)?;
+        let severity = match &captures[1] {
+            "ERROR" => Severity::Error,
+            "WARNING" => Severity::Warning,
+            _ => Severity::Info,
+        };
+
+        log_line(line, &captures, 2, 3, 4, Some(5), Some(severity))
+    }
+}
+
+/// `0(12) : error C1008: message`, as printed by NVIDIA drivers
+pub struct NvidiaLogParser;
+
+impl LogParser for NvidiaLogParser {
+    fn parse_line(&self, line: &str) -> Option<LogLine> {
+        lazy_static::lazy_static! {
+            static ref RE: regex::Regex = regex::Regex::new(
+                r"^((\d+)\((\d+)\))\s*(?::\s*(?i:(error|warning|info|note))\b)?"
+            )
+            .unwrap();
+        }
+
+        let captures = RE.captures(line)?;
+        let severity = captures.get(4).map(|s| severity_from_word(s.as_str()));
+
+        log_line(line, &captures, 1, 2, 3, None, severity)
+    }
+}
+
+/// `0:12(5): error: message`, as printed by Mesa drivers
+pub struct MesaLogParser;
+
+impl LogParser for MesaLogParser {
+    fn parse_line(&self, line: &str) -> Option<LogLine> {
+        lazy_static::lazy_static! {
+            static ref RE: regex::Regex = regex::Regex::new(
+                r"^((\d+):(\d+)\((\d+)\)):\s*(?:preprocessor )?(error|warning|info)\b"
+            )
+            .unwrap();
+        }
+
+        let captures = RE.captures(line)?;
+        let severity = severity_from_word(&captures[5]);
+
+        log_line(line, &captures, 1, 2, 3, Some(4), Some(severity))
+    }
+}
+
+/// `0:12: L0002: message`, as printed by ARM Mali drivers. Codes starting with `W`
+/// are warnings; all others are errors.
+pub struct MaliLogParser;
+
+impl LogParser for MaliLogParser {
+    fn parse_line(&self, line: &str) -> Option<LogLine> {
+        lazy_static::lazy_static! {
+            static ref RE: regex::Regex =
+                regex::Regex::new(r"^((\d+):(\d+)):\s*([A-Z])\d{4}:").unwrap();
+        }
+
+        let captures = RE.captures(line)?;
+        let severity = if &captures[4] == "W" {
+            Severity::Warning
+        } else {
+            Severity::Error
+        };
+
+        let mut log_line = log_line(line, &captures, 1, 2, 3, None, Some(severity))?;
+        // Keep the message code
+        log_line.message.start = captures.get(4)?.start();
+        Some(log_line)
+    }
+}
+
+/// Parsers used by `compile_shader`, covering the drivers listed with each
+pub const DEFAULT_LOG_PARSERS: &[&dyn LogParser] = &[
+    &GlslangStyleLogParser,
+    &NvidiaLogParser,
+    &MesaLogParser,
+    &MaliLogParser,
+];
+
+fn severity_from_word(word: &str) -> Severity {
+    match word.to_ascii_lowercase().as_str() {
+        "error" => Severity::Error,
+        "warning" => Severity::Warning,
+        _ => Severity::Info,
+    }
+}
+
+/// Builds a `LogLine` from the capture groups of a parser's regex. The message follows
+/// the whole match, skipping any separating colon and whitespace.
+fn log_line(
+    line: &str,
+    captures: &regex::Captures,
+    location_group: usize,
+    source_string_group: usize,
+    line_group: usize,
+    column_group: Option<usize>,
+    severity: Option<Severity>,
+) -> Option<LogLine> {
+    let rest = &line[captures.get(0)?.end()..];
+    let message_start = line.len() - rest.trim_start_matches(':').trim_start().len();
+
+    // Numbers too large for `usize` still make a location, just not one we can map.
+    Some(LogLine {
+        location: captures.get(location_group)?.range(),
+        source_string: captures.get(source_string_group)?.as_str().parse().ok(),
+        line: captures.get(line_group)?.as_str().parse().ok(),
+        column: column_group
+            .and_then(|group| captures.get(group))
+            .and_then(|column| column.as_str().parse().ok()),
+        severity,
+        message: message_start..line.trim_end().len(),
+    })
+}
+
 /// Compile a shader defined as one or more `SourceChunk`s via a used-provided
 /// shader compiler callback.
 ///
@@ -93,66 +273,191 @@ pub struct ShaderCompilerOutput<Artifact> {
 ///
 /// `compiler_fn` is a function which, given a list of source strings
 /// (which this function generates from `source_chunks`), creates a `ShaderCompilerOutput`.
+/// The strings are the chunks' sources, interleaved with `#line` directives.
+///
+/// The compiler's log is parsed using [`DEFAULT_LOG_PARSERS`].
 pub fn compile_shader<'chunk, ChunksIter, Artifact, CompilerFn, IncludeContext>(
     source_chunks: ChunksIter,
     compiler_fn: CompilerFn,
-) -> ShaderCompilerOutput<Artifact>
+) -> CompiledShader<Artifact>
+where
+    ChunksIter: Iterator<Item = &'chunk crate::source_chunk::SourceChunk<IncludeContext>>,
+    IncludeContext: 'chunk,
+    CompilerFn: FnMut(&[&str]) -> ShaderCompilerOutput<Artifact>,
+{
+    compile_shader_with_log_parsers(source_chunks, DEFAULT_LOG_PARSERS, compiler_fn)
+}
+
+/// Like `compile_shader`, but with a fallible compiler callback, whose error is passed through.
+pub fn try_compile_shader<'chunk, ChunksIter, Artifact, Error, CompilerFn, IncludeContext>(
+    source_chunks: ChunksIter,
+    compiler_fn: CompilerFn,
+) -> Result<CompiledShader<Artifact>, Error>
+where
+    ChunksIter: Iterator<Item = &'chunk crate::source_chunk::SourceChunk<IncludeContext>>,
+    IncludeContext: 'chunk,
+    CompilerFn: FnMut(&[&str]) -> Result<ShaderCompilerOutput<Artifact>, Error>,
+{
+    try_compile_shader_with_log_parsers(source_chunks, DEFAULT_LOG_PARSERS, compiler_fn)
+}
+
+/// Like `compile_shader`, but with a custom list of log parsers. For each line of the log,
+/// the first parser recognizing it is used.
+///
+/// Locations which don't correspond to any chunk, e.g. due to an out-of-range source string index
+/// or a number too large to parse, are left as they are in the log, followed by `(unmapped)`, and produce diagnostics
+/// without a `file`.
+pub fn compile_shader_with_log_parsers<'chunk, ChunksIter, Artifact, CompilerFn, IncludeContext>(
+    source_chunks: ChunksIter,
+    log_parsers: &[&dyn LogParser],
+    mut compiler_fn: CompilerFn,
+) -> CompiledShader<Artifact>
+where
+    ChunksIter: Iterator<Item = &'chunk crate::source_chunk::SourceChunk<IncludeContext>>,
+    IncludeContext: 'chunk,
+    CompilerFn: FnMut(&[&str]) -> ShaderCompilerOutput<Artifact>,
+{
+    let result = try_compile_shader_with_log_parsers(source_chunks, log_parsers, |sources| {
+        Ok::<_, std::convert::Infallible>(compiler_fn(sources))
+    });
+
+    match result {
+        Ok(compiled) => compiled,
+        Err(never) => match never {},
+    }
+}
+
+/// Combination of `try_compile_shader` and `compile_shader_with_log_parsers`.
+pub fn try_compile_shader_with_log_parsers<
+    'chunk,
+    ChunksIter,
+    Artifact,
+    Error,
+    CompilerFn,
+    IncludeContext,
+>(
+    source_chunks: ChunksIter,
+    log_parsers: &[&dyn LogParser],
+    mut compiler_fn: CompilerFn,
+) -> Result<CompiledShader<Artifact>, Error>
 where
     ChunksIter: Iterator<Item = &'chunk crate::source_chunk::SourceChunk<IncludeContext>>,
     IncludeContext: 'chunk,
-    CompilerFn: Fn(&[String]) -> ShaderCompilerOutput<Artifact>,
+    CompilerFn: FnMut(&[&str]) -> Result<ShaderCompilerOutput<Artifact>, Error>,
 {
     struct FileAndLineOffset {
         file: String,
         line_offset: usize,
     }
 
-    let (sources, file_and_line_offset): (Vec<String>, Vec<FileAndLineOffset>) = source_chunks
+    let source_chunks: Vec<_> = source_chunks.collect();
+
+    // Each chunk after the first is preceded by a separate string assigning it
+    // a source string number, so that the chunks themselves needn't be copied.
+    let line_directives: Vec<String> = source_chunks
+        .iter()
         .enumerate()
-        .map(|(i, s)| {
-            (
-                if i == 0 {
-                    s.source.clone()
-                } else {
-                    format!("#line 0 {}\n{}", i + 1, s.source)
-                },
-                FileAndLineOffset {
-                    file: s.file.clone(),
-                    line_offset: s.line_offset,
-                },
-            )
+        .skip(1)
+        .map(|(i, _)| {
+            // The directive must start a line; the previous chunk might not end with one.
+            let newline = if source_chunks[i - 1].source.ends_with('\n') {
+                ""
+            } else {
+                "\n"
+            };
+            format!("{}#line 0 {}\n", newline, i + 1)
         })
-        .unzip();
-
-    let compiler_output = compiler_fn(&sources);
+        .collect();
 
-    lazy_static::lazy_static! {
-        static ref INTEL_AMD_ERROR_RE: regex::Regex = regex::Regex::new(r"(?m)^ERROR:\s*(\d+):(\d+)").unwrap();
+    let mut sources: Vec<&str> = Vec::with_capacity(source_chunks.len() * 2);
+    for (i, chunk) in source_chunks.iter().enumerate() {
+        if i > 0 {
+            sources.push(&line_directives[i - 1]);
+        }
+        sources.push(&chunk.source);
     }
 
-    lazy_static::lazy_static! {
-        static ref NV_ERROR_RE: regex::Regex = regex::Regex::new(r"(?m)^(\d+)\((\d+)\)\s*").unwrap();
-    }
+    let file_and_line_offset: Vec<FileAndLineOffset> = source_chunks
+        .iter()
+        .map(|s| FileAndLineOffset {
+            file: s.file.clone(),
+            line_offset: s.line_offset,
+        })
+        .collect();
+
+    let compiler_output = compiler_fn(&sources)?;
+
+    let mut diagnostics = Vec::new();
+
+    let mut remap_line = |line_str: &str| -> String {
+        let parsed = match log_parsers
+            .iter()
+            .find_map(|parser| parser.parse_line(line_str))
+        {
+            // Ranges from custom parsers might not be valid.
+            Some(parsed)
+                if line_str.get(parsed.location.clone()).is_some()
+                    && line_str.get(parsed.message.clone()).is_some() =>
+            {
+                parsed
+            }
+            _ => return line_str.to_owned(),
+        };
+
+        let mapped = parsed
+            .source_string
+            .zip(parsed.line)
+            .and_then(|(source_string, line)| {
+                let chunk_idx = source_string.max(1) - 1;
+                let chunk = file_and_line_offset.get(chunk_idx)?;
+
+                // Lines of the first chunk are one-based, while the others follow `#line 0`.
+                let line_base = if chunk_idx == 0 { 0 } else { 1 };
+
+                Some((
+                    chunk.file.clone(),
+                    line.checked_add(chunk.line_offset)?
+                        .checked_add(line_base)?,
+                ))
+            });
+
+        if let Some(severity) = parsed.severity {
+            diagnostics.push(Diagnostic {
+                severity,
+                file: mapped.as_ref().map(|(file, _)| file.clone()),
+                line: mapped.as_ref().map_or(parsed.line, |(_, line)| Some(*line)),
+                column: parsed.column,
+                message: line_str[parsed.message.clone()].to_owned(),
+            });
+        }
+
+        let location = match (mapped, parsed.column) {
+            (Some((file, line)), Some(column)) => format!("{}({},{})", file, line, column),
+            (Some((file, line)), None) => format!("{}({})", file, line),
+            (None, _) => format!("{} (unmapped)", &line_str[parsed.location.clone()]),
+        };
 
-    let error_replacement = |captures: &regex::Captures| -> String {
-        let chunk = captures[1].parse::<usize>().unwrap().max(1) - 1;
-        let line = captures[2].parse::<usize>().unwrap();
         format!(
-            "{}({})",
-            file_and_line_offset[chunk].file,
-            line + file_and_line_offset[chunk].line_offset
+            "{}{}{}",
+            &line_str[..parsed.location.start],
+            location,
+            &line_str[parsed.location.end..]
         )
     };
 
     let pretty_log = compiler_output.log.map(|log_str| {
-        let log_str = INTEL_AMD_ERROR_RE.replace_all(&log_str, error_replacement);
-        NV_ERROR_RE
-            .replace_all(&log_str, error_replacement)
-            .into_owned()
+        log_str
+            .split_inclusive('\n')
+            .map(|line_str| match line_str.strip_suffix('\n') {
+                Some(line_str) => remap_line(line_str) + "\n",
+                None => remap_line(line_str),
+            })
+            .collect()
     });
 
-    ShaderCompilerOutput {
+    Ok(CompiledShader {
         artifact: compiler_output.artifact,
         log: pretty_log,
-    }
+        diagnostics,
+    })
 }
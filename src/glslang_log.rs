@@ -0,0 +1,81 @@
+//! Remapping of glslangValidator, glslc and shaderc messages about shaders compiled as a single
+//! file, e.g. the concatenated sources of all `SourceChunk`s, back to the original files.
+//!
+//! Two message formats are recognized:
+//!
+//! * `ERROR: file.glsl:12: message` (glslangValidator),
+//! * `file.glsl:12: error: message` (glslc and shaderc),
+//!
+//! each optionally with a column following the line. Only locations in the compiled file,
+//! as named by `file_name`, are remapped; messages about other files are left as they are.
+
+pub use crate::log_remap::RemappedLog;
+
+use crate::log_remap::{
+    colon_location, parse_colon_location, parse_severity, remap_lines, remap_location,
+};
+use crate::{Diagnostic, Severity, SourceMap};
+
+/// Remap locations in `log`, referring to lines of the compiled file `file_name`,
+/// using the `source_map` built from the chunks it was assembled from.
+///
+/// Locations which don't correspond to any chunk are left as they are,
+/// followed by `(unmapped)`, and produce diagnostics without a `file`.
+pub fn remap_glslang_log(log: &str, file_name: &str, source_map: &SourceMap) -> RemappedLog {
+    let mut diagnostics = Vec::new();
+
+    let log = remap_lines(log, |line_str| {
+        remap_line(line_str, file_name, source_map, &mut diagnostics)
+    });
+
+    RemappedLog { log, diagnostics }
+}
+
+fn remap_line(
+    line_str: &str,
+    file_name: &str,
+    source_map: &SourceMap,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> String {
+    const GLSLANG_SEVERITIES: &[(&str, Severity)] = &[
+        ("ERROR: ", Severity::Error),
+        ("WARNING: ", Severity::Warning),
+        ("INFO: ", Severity::Info),
+        ("NOTE: ", Severity::Info),
+    ];
+
+    const GLSLC_SEVERITIES: &[(&str, Severity)] = &[
+        ("error:", Severity::Error),
+        ("fatal error:", Severity::Error),
+        ("warning:", Severity::Warning),
+        ("note:", Severity::Info),
+        ("info:", Severity::Info),
+    ];
+
+    let glslang_severity = GLSLANG_SEVERITIES
+        .iter()
+        .find(|(prefix, _)| line_str.starts_with(prefix));
+    let location_start = glslang_severity.map_or(0, |(prefix, _)| prefix.len());
+
+    let location = match parse_colon_location(line_str, location_start, file_name) {
+        Some(location) => location,
+        None => return line_str.to_owned(),
+    };
+
+    let rest = line_str[location.range.end..]
+        .trim_start_matches(':')
+        .trim_start();
+    let severity = match glslang_severity {
+        Some((_, severity)) => Some((*severity, rest)),
+        None => parse_severity(rest, GLSLC_SEVERITIES),
+    };
+
+    remap_location(
+        line_str,
+        &location,
+        severity,
+        source_map,
+        diagnostics,
+        |original| colon_location(original, location.column),
+    )
+}
@@ -0,0 +1,129 @@
+//! Remapping of DXC and FXC messages about shaders compiled as a single file, e.g. the
+//! concatenated sources of all `SourceChunk`s, back to the original files.
+//!
+//! Two message formats are recognized:
+//!
+//! * `file.hlsl(12,5): error X3000: message` (FXC, and DXC in its FXC-compatible mode),
+//!   where the column may be a range such as `5-10`, or missing altogether,
+//! * `file.hlsl:12:5: error: message` (DXC).
+//!
+//! Only locations in the compiled file, as named by `file_name`, are remapped; messages
+//! about other files are left as they are.
+
+pub use crate::log_remap::RemappedLog;
+
+use crate::log_remap::{
+    colon_location, parse_colon_location, parse_number, remap_lines, remap_location, LogLocation,
+};
+use crate::{Diagnostic, Severity, SourceMap};
+
+/// Remap locations in `log`, referring to lines of the compiled file `file_name`,
+/// using the `source_map` built from the chunks it was assembled from.
+///
+/// Locations which don't correspond to any chunk are left as they are,
+/// followed by `(unmapped)`, and produce diagnostics without a `file`.
+pub fn remap_dxc_log(log: &str, file_name: &str, source_map: &SourceMap) -> RemappedLog {
+    let mut diagnostics = Vec::new();
+
+    let log = remap_lines(log, |line_str| {
+        remap_line(line_str, file_name, source_map, &mut diagnostics)
+    });
+
+    RemappedLog { log, diagnostics }
+}
+
+fn remap_line(
+    line_str: &str,
+    file_name: &str,
+    source_map: &SourceMap,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> String {
+    // `Some` with the last column of the range, if any, for FXC messages such as `file(12,5-10)`
+    let (location, fxc_end_column) = match parse_fxc_location(line_str, file_name) {
+        Some((location, end_column)) => (location, Some(end_column)),
+        None => match parse_colon_location(line_str, 0, file_name) {
+            Some(location) => (location, None),
+            None => return line_str.to_owned(),
+        },
+    };
+
+    let rest = line_str[location.range.end..]
+        .trim_start_matches(':')
+        .trim_start();
+
+    remap_location(
+        line_str,
+        &location,
+        parse_severity(rest),
+        source_map,
+        diagnostics,
+        |original| {
+            let line = original.line + 1;
+
+            match (location.column, fxc_end_column) {
+                (Some(column), Some(end_column)) => {
+                    let end_column = end_column
+                        .map(|end| format!("-{}", end.saturating_sub(column) + original.column + 1))
+                        .unwrap_or_default();
+                    format!(
+                        "{}({},{}{})",
+                        original.file,
+                        line,
+                        original.column + 1,
+                        end_column
+                    )
+                }
+                (None, Some(_)) => format!("{}({})", original.file, line),
+                (_, None) => colon_location(original, location.column),
+            }
+        },
+    )
+}
+
+/// Parses `file_name(line)`, `file_name(line,column)` or `file_name(line,column-column)`
+/// at the start of `s`, along with the last column of the range, if any.
+fn parse_fxc_location(s: &str, file_name: &str) -> Option<(LogLocation, Option<usize>)> {
+    let rest = s.strip_prefix(file_name)?.strip_prefix('(')?;
+    let (line, rest) = parse_number(rest)?;
+
+    let (column, end_column, rest) = match rest.strip_prefix(',').and_then(parse_number) {
+        Some((column, rest)) => match rest.strip_prefix('-').and_then(parse_number) {
+            Some((end_column, rest)) => (Some(column), Some(end_column), rest),
+            None => (Some(column), None, rest),
+        },
+        None => (None, None, rest),
+    };
+
+    let rest = rest.strip_prefix(')')?;
+
+    let location = LogLocation {
+        line,
+        column,
+        range: 0..s.len() - rest.len(),
+    };
+
+    Some((location, end_column))
+}
+
+/// Parses the severity following the location. For FXC messages, the error code
+/// (e.g. `X3000: `) is kept at the start of the message.
+fn parse_severity(s: &str) -> Option<(Severity, &str)> {
+    const SEVERITIES: &[(&str, Severity)] = &[
+        ("error", Severity::Error),
+        ("fatal error", Severity::Error),
+        ("warning", Severity::Warning),
+        ("note", Severity::Info),
+        ("remark", Severity::Info),
+    ];
+
+    SEVERITIES.iter().find_map(|(prefix, severity)| {
+        let rest = s.strip_prefix(prefix)?;
+        let message = if let Some(message) = rest.strip_prefix(':') {
+            message
+        } else {
+            rest.strip_prefix(' ')?
+        };
+
+        Some((*severity, message.trim_start()))
+    })
+}
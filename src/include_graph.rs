@@ -0,0 +1,84 @@
+use crate::{json, ResolvedIncludePath};
+
+/// Files visited while crawling, and the `#include` directives between them
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IncludeGraph {
+    /// Resolved files, starting with the root file, in the order first encountered
+    pub files: Vec<ResolvedIncludePath>,
+
+    /// `#include` directives, in the order encountered. Directives resolving to files
+    /// which were then skipped, e.g. due to `#pragma once`, are included too.
+    pub edges: Vec<IncludeEdge>,
+}
+
+/// Single `#include` directive in an [`IncludeGraph`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IncludeEdge {
+    /// Index of the including file in [`IncludeGraph::files`]
+    pub from: usize,
+
+    /// Index of the included file in [`IncludeGraph::files`]
+    pub to: usize,
+
+    /// Line of the directive in the including file
+    pub line: usize,
+}
+
+impl IncludeGraph {
+    /// Index of `file` in `files`, adding it if not already there.
+    pub(crate) fn add_file(&mut self, file: &ResolvedIncludePath) -> usize {
+        match self.files.iter().position(|f| f == file) {
+            Some(idx) => idx,
+            None => {
+                self.files.push(file.clone());
+                self.files.len() - 1
+            }
+        }
+    }
+
+    /// Serialize as a [Graphviz](https://graphviz.org) DOT digraph, with edges labeled by line.
+    pub fn to_dot(&self) -> String {
+        let mut result = String::from("digraph includes {\n");
+
+        for (idx, file) in self.files.iter().enumerate() {
+            // JSON string escaping also works for DOT labels, bar control characters.
+            result.push_str(&format!(
+                "    n{} [label={}];\n",
+                idx,
+                json::string(&file.0)
+            ));
+        }
+
+        for edge in &self.edges {
+            result.push_str(&format!(
+                "    n{} -> n{} [label=\"{}\"];\n",
+                edge.from, edge.to, edge.line
+            ));
+        }
+
+        result.push_str("}\n");
+        result
+    }
+
+    /// Serialize as a JSON object with `files` (an array of paths) and `edges`
+    /// (an array of `{"from", "to", "line"}` objects, indexing into `files`).
+    pub fn to_json(&self) -> String {
+        let files: Vec<String> = self.files.iter().map(|f| json::string(&f.0)).collect();
+        let edges: Vec<String> = self
+            .edges
+            .iter()
+            .map(|e| {
+                format!(
+                    "{{\"from\":{},\"to\":{},\"line\":{}}}",
+                    e.from, e.to, e.line
+                )
+            })
+            .collect();
+
+        format!(
+            "{{\"files\":[{}],\"edges\":[{}]}}",
+            files.join(","),
+            edges.join(",")
+        )
+    }
+}
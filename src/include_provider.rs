@@ -1,8 +1,20 @@
 use crate::BoxedIncludeProviderError;
 
-#[derive(Clone, PartialEq, Eq, Hash)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct ResolvedIncludePath(pub String);
 
+/// Delimiters used by an `#include` directive
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum IncludeKind {
+    /// `#include "path"`, conventionally used for local files.
+    /// Also used for the root file passed to `process_file`.
+    Quoted,
+
+    /// `#include <path>`, conventionally used for system or library files
+    AngleBracket,
+}
+
+#[derive(Clone)]
 pub struct ResolvedInclude<IncludeContext> {
     pub resolved_path: ResolvedIncludePath,
     pub context: IncludeContext,
@@ -12,9 +24,15 @@ pub struct ResolvedInclude<IncludeContext> {
 pub trait IncludeProvider {
     type IncludeContext;
 
+    /// Resolve `path` as found in an `#include` directive of the given `kind`.
+    ///
+    /// `includer` is the resolved path of the file containing the directive,
+    /// or `None` for the root file. `context` is the one associated with `includer`.
     fn resolve_path(
         &self,
         path: &str,
+        kind: IncludeKind,
+        includer: Option<&ResolvedIncludePath>,
         context: &Self::IncludeContext,
     ) -> Result<ResolvedInclude<Self::IncludeContext>, BoxedIncludeProviderError>;
 
@@ -1,15 +1,46 @@
 //! **shader-prepper** is a shader include parser and crawler. It is mostly aimed at GLSL
 //! which doesn't provide include directive support out of the box.
 //!
-//! This crate does not implement a full C-like preprocessor, only `#include` scanning.
-//! Other directives are instead copied into the expanded code, so they can be subsequently
-//! handled by the shader compiler.
+//! By default, this crate does not act as a full C-like preprocessor, and only scans `#include`
+//! directives. Other directives are instead copied into the expanded code, so they can be
+//! subsequently handled by the shader compiler.
+//!
+//! Conditional directives (`#if`, `#ifdef` and friends) can optionally be evaluated while
+//! crawling (see `ProcessOptions`), so that includes in inactive branches are skipped.
+//! With the `macros` feature, the scanner can also expand macros, producing fully
+//! preprocessed code for backends with limited preprocessor support.
 //!
 //! The API supports user-driven include file providers, which enable custom
 //! virtual file systems, include paths, and allow build systems to track dependencies.
+//! For reading straight from the filesystem, `FsIncludeProvider` implements the usual
+//! C-like search: relative to the including file first, then in a list of include directories.
+//! `CachingIncludeProvider` wraps any provider, memoizing its results and hashing file contents,
+//! which helps when processing many shaders sharing the same headers. A `Preprocessor` session
+//! goes further, reusing the scan results of shared headers across root files.
+//! `process_file_with_output` additionally reports every file the output depends on,
+//! and can format them as a Makefile-style depfile for build systems such as make or ninja.
+//! The full include graph is available there too, exportable as Graphviz DOT or JSON.
+//! For live shader editing, the `watch` feature provides `ShaderWatcher`, which watches
+//! every file read while processing, and reports the root shaders affected by changes.
+//! HLSL is supported via `ProcessOptions::dialect`, with `HlslIncludeProvider` resolving includes
+//! relative to the including file and then its includers, as DXC does.
+//! WGSL is supported too, with nested block comments, and `#import "file"` as an alternative
+//! to `#include`.
+//! With `ProcessOptions::module_imports`, `#import some::module` and `#import some::module::{a, b}`
+//! bring in modules in the style of naga_oil, in every dialect: `some/module.wgsl` (or `.glsl`,
+//! `.hlsl`) is requested from the `IncludeProvider` as an angle-bracket include, included once
+//! per root, and its top-level declarations are renamed to e.g. `some_module__a`, as are
+//! references to them via `module::a`, or via `a` when listed in braces.
 //!
 //! Source files are not concatenated together, but returned as a Vec of [`SourceChunk`].
-//! If a single string is needed, a `join` over the source strings can be used.
+//! If a single string is needed, a `join` over the source strings can be used,
+//! and `SourceMap` can then map locations in it back to the original files.
+//! Alternatively, `to_string_with_line_directives` produces a single string with `#line`
+//! directives at chunk boundaries, letting the compiler itself report the original locations.
+//! When the joined string is compiled with glslangValidator or glslc, `glslang_log::remap_glslang_log`
+//! rewrites the locations in their messages back to the original files.
+//! For HLSL, `hlsl_log::remap_dxc_log` does the same for DXC and FXC messages,
+//! and for WGSL, `wgsl_log::remap_wgsl_log` for naga and Tint messages.
 //! Otherwise, the individual chunks can be passed to the graphics API, and source info
 //! contained within `SourceChunk` can then remap the compiler's errors back to
 //! the original code.
@@ -25,6 +56,8 @@
 //!     fn resolve_path(
 //!         &self,
 //!         path: &str,
+//!         _kind: shader_prepper::IncludeKind,
+//!         _includer: Option<&shader_prepper::ResolvedIncludePath>,
 //!         _context: &Self::IncludeContext,
 //!     ) -> Result<shader_prepper::ResolvedInclude<Self::IncludeContext>, shader_prepper::BoxedIncludeProviderError>
 //!     {
@@ -49,10 +82,29 @@
 
 #![cfg_attr(docsrs, feature(doc_cfg))]
 
+mod caching_include_provider;
+mod defines;
+mod diagnostic;
 mod error;
+mod expression;
+mod fs_include_provider;
+mod include_graph;
 mod include_provider;
+mod json;
+mod line_directives;
+mod log_remap;
+mod macros;
+mod modules;
+mod options;
+mod pp_token;
+mod preamble;
+mod preprocessor;
+mod process_output;
 mod scanner;
 mod source_chunk;
+mod source_map;
+#[cfg(feature = "watch")]
+mod watch;
 
 #[cfg(test)]
 mod tests;
@@ -61,10 +113,22 @@ mod tests;
 #[cfg(feature = "gl_compiler")]
 pub mod gl_compiler;
 
-use std::collections::HashSet;
+pub mod glslang_log;
+
+pub mod hlsl_log;
+
+pub mod wgsl_log;
 
-use scanner::Scanner;
-pub use {error::*, include_provider::*, source_chunk::*};
+use scanner::{Scanner, ScannerState};
+pub use {
+    caching_include_provider::*, diagnostic::*, error::*, fs_include_provider::*, include_graph::*,
+    include_provider::*, line_directives::*, log_remap::RemappedLog, options::*, preprocessor::*,
+    process_output::*, source_chunk::*, source_map::*,
+};
+
+#[cfg_attr(docsrs, doc(cfg(feature = "watch")))]
+#[cfg(feature = "watch")]
+pub use watch::ShaderWatcher;
 
 /// Process a single file, and then any code recursively referenced.
 ///
@@ -74,17 +138,103 @@ pub fn process_file<IncludeContext: Clone>(
     include_provider: &mut dyn IncludeProvider<IncludeContext = IncludeContext>,
     include_context: IncludeContext,
 ) -> Result<Vec<SourceChunk<IncludeContext>>, BoxedIncludeProviderError> {
-    let mut prior_includes = HashSet::new();
-    let mut skip_includes = HashSet::new();
+    Ok(process_file_with_options(
+        file_path,
+        include_provider,
+        include_context,
+        &ProcessOptions::default(),
+    )?)
+}
+
+/// Like [`process_file`], but with additional control over the processing via [`ProcessOptions`].
+///
+/// Errors are reported as [`PrepperError`], carrying the include stack at the point of failure.
+pub fn process_file_with_options<IncludeContext: Clone>(
+    file_path: &str,
+    include_provider: &mut dyn IncludeProvider<IncludeContext = IncludeContext>,
+    include_context: IncludeContext,
+    options: &ProcessOptions,
+) -> Result<Vec<SourceChunk<IncludeContext>>, PrepperError> {
+    Ok(process_file_with_output(file_path, include_provider, include_context, options)?.chunks)
+}
+
+/// Like [`process_file_with_options`], but also returns information gathered while crawling,
+/// such as the files the output depends on. See [`ProcessOutput`].
+pub fn process_file_with_output<IncludeContext: Clone>(
+    file_path: &str,
+    include_provider: &mut dyn IncludeProvider<IncludeContext = IncludeContext>,
+    include_context: IncludeContext,
+    options: &ProcessOptions,
+) -> Result<ProcessOutput<IncludeContext>, PrepperError> {
+    process_file_with_state(
+        file_path,
+        include_provider,
+        include_context,
+        &mut ScannerState::new(options.clone()),
+    )
+}
+
+fn process_file_with_state<IncludeContext: Clone>(
+    file_path: &str,
+    include_provider: &mut dyn IncludeProvider<IncludeContext = IncludeContext>,
+    include_context: IncludeContext,
+    state: &mut ScannerState,
+) -> Result<ProcessOutput<IncludeContext>, PrepperError> {
+    let mut prelude = String::new();
+
+    for (i, (name, value)) in state.options.defines.clone().iter().enumerate() {
+        let directive_args = if value.is_empty() {
+            name.clone()
+        } else {
+            format!("{} {}", name, value)
+        };
+
+        let (name, definition) =
+            defines::parse_define(&directive_args).ok_or_else(|| PrepperError::DirectiveError {
+                file: DEFINES_PRELUDE_FILE.to_string(),
+                line: i + 1,
+                message: "malformed #define".to_string(),
+                include_stack: Vec::new(),
+            })?;
+
+        state.defines.insert(name, definition);
+        prelude.push_str(&format!("#define {}\n", directive_args));
+    }
 
     let mut scanner = Scanner::new(
         "",
         String::new(),
-        &mut prior_includes,
-        &mut skip_includes,
+        state,
         include_provider,
-        include_context,
+        include_context.clone(),
     );
-    scanner.include_child(file_path, 1)?;
-    Ok(scanner.into_chunks())
+    scanner.include_child(file_path, IncludeKind::Quoted, 1)?;
+
+    let mut chunks = scanner.into_chunks();
+
+    if cfg!(feature = "macros") && state.options.expand_macros {
+        // Already applied by the scanner
+        prelude.clear();
+    }
+
+    let prelude = if prelude.is_empty() {
+        None
+    } else {
+        Some(SourceChunk {
+            source: prelude,
+            context: include_context,
+            file: DEFINES_PRELUDE_FILE.to_string(),
+            line_offset: 0,
+            column_offset: 0,
+        })
+    };
+
+    modules::mangle_chunks(&mut chunks, &state.imports, state.options.dialect);
+    preamble::insert_preamble(&mut chunks, prelude, state.options.hoist_extensions);
+
+    Ok(ProcessOutput {
+        chunks,
+        dependencies: state.include_graph.files.clone(),
+        include_graph: std::mem::take(&mut state.include_graph),
+    })
 }
@@ -0,0 +1,101 @@
+use crate::defines::split_ident;
+use crate::SourceChunk;
+
+/// Form of the `#line` directives emitted by [`to_string_with_line_directives`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LineDirectiveStyle {
+    /// `#line N`. Only restores line numbers; the file is lost.
+    Line,
+
+    /// `#line N "file"`, as allowed by the `GL_GOOGLE_cpp_style_line_directive` extension
+    /// understood by glslang and shaderc. The extension is enabled by the output itself.
+    LineAndFile,
+
+    /// `#line N "file"`, as understood by DXC and FXC. Backslashes and quotes in the file name
+    /// are escaped.
+    Hlsl,
+}
+
+/// Concatenate the sources of `chunks`, preceding each chunk with a `#line` directive
+/// pointing back at its origin, so that compiler messages refer to the original files.
+///
+/// The directives follow the C convention (also that of GLSL 330 and GLSL ES 300 onwards),
+/// where `#line N` sets the number of the following line to `N`.
+///
+/// A `#version` directive at the start of the first chunk is kept ahead of everything else,
+/// as GLSL requires.
+pub fn to_string_with_line_directives<IncludeContext>(
+    chunks: &[SourceChunk<IncludeContext>],
+    style: LineDirectiveStyle,
+) -> String {
+    let mut result = String::new();
+
+    for (i, chunk) in chunks.iter().enumerate() {
+        let mut source = chunk.source.as_str();
+        let mut line = chunk.line_offset + 1;
+
+        if i == 0 {
+            let (version, rest) = split_version_directive(source);
+            result.push_str(version);
+            line += version.matches('\n').count();
+            source = rest;
+
+            if style == LineDirectiveStyle::LineAndFile {
+                if !result.is_empty() && !result.ends_with('\n') {
+                    result.push('\n');
+                }
+                result.push_str("#extension GL_GOOGLE_cpp_style_line_directive : enable\n");
+            }
+        }
+
+        if source.is_empty() {
+            continue;
+        }
+
+        if !result.is_empty() && !result.ends_with('\n') {
+            result.push('\n');
+        }
+
+        match style {
+            LineDirectiveStyle::Line => result.push_str(&format!("#line {}\n", line)),
+            LineDirectiveStyle::LineAndFile => {
+                result.push_str(&format!("#line {} \"{}\"\n", line, chunk.file))
+            }
+            LineDirectiveStyle::Hlsl => result.push_str(&format!(
+                "#line {} \"{}\"\n",
+                line,
+                chunk.file.replace('\\', "\\\\").replace('"', "\\\"")
+            )),
+        }
+
+        result.push_str(source);
+    }
+
+    result
+}
+
+/// Splits `source` after its `#version` directive line, if it starts with one.
+/// Only whitespace may precede the directive; comments are already removed by the scanner.
+pub(crate) fn split_version_directive(source: &str) -> (&str, &str) {
+    let directive_start = source.len() - source.trim_start().len();
+    let is_version = source[directive_start..]
+        .strip_prefix('#')
+        .and_then(split_ident)
+        .is_some_and(|(name, _)| name == "version");
+
+    if !is_version {
+        return ("", source);
+    }
+
+    let mut end = source.len();
+    let mut prev = '\0';
+    for (idx, c) in source[directive_start..].char_indices() {
+        if c == '\n' && prev != '\\' {
+            end = directive_start + idx + 1;
+            break;
+        }
+        prev = c;
+    }
+
+    source.split_at(end)
+}
@@ -0,0 +1,133 @@
+//! Parts shared by the remapping of compiler logs about shaders compiled as a single file,
+//! e.g. the concatenated sources of all `SourceChunk`s, back to the original files.
+
+use std::ops::Range;
+
+use crate::{Diagnostic, Severity, SourceLocation, SourceMap};
+
+/// Compiler log with locations rewritten to refer to the original files
+pub struct RemappedLog {
+    pub log: String,
+
+    /// Messages parsed out of the log, in order. Lines and columns are one-based.
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// Location in the compiled file, as found in a line of the log
+pub(crate) struct LogLocation {
+    pub line: usize,
+    pub column: Option<usize>,
+
+    /// Byte range of the location within the line
+    pub range: Range<usize>,
+}
+
+/// Rewrites `log` line by line. `remap_line` is given each line without its line ending,
+/// which is kept as it is.
+pub(crate) fn remap_lines<'a>(
+    log: &'a str,
+    mut remap_line: impl FnMut(&'a str) -> String,
+) -> String {
+    log.split_inclusive('\n')
+        .map(|line_str| match line_str.strip_suffix('\n') {
+            Some(line_str) => remap_line(line_str) + "\n",
+            None => remap_line(line_str),
+        })
+        .collect()
+}
+
+/// Maps `location` within `line_str` through `source_map`, recording a diagnostic if the message
+/// has a `severity`. Returns `line_str` with the location replaced by `format_location`
+/// of the original one, or followed by `(unmapped)` if there is none.
+pub(crate) fn remap_location(
+    line_str: &str,
+    location: &LogLocation,
+    severity: Option<(Severity, &str)>,
+    source_map: &SourceMap,
+    diagnostics: &mut Vec<Diagnostic>,
+    format_location: impl FnOnce(&SourceLocation) -> String,
+) -> String {
+    let original = source_map.original_location(
+        location.line.saturating_sub(1),
+        location.column.unwrap_or(1).saturating_sub(1),
+    );
+
+    if let Some((severity, message)) = severity {
+        diagnostics.push(Diagnostic {
+            severity,
+            file: original.as_ref().map(|o| o.file.clone()),
+            line: Some(original.as_ref().map_or(location.line, |o| o.line + 1)),
+            column: match (&original, location.column) {
+                (Some(o), Some(_)) => Some(o.column + 1),
+                _ => location.column,
+            },
+            message: message.trim_end().to_owned(),
+        });
+    }
+
+    let remapped = match original {
+        Some(o) => format_location(&o),
+        None => format!("{} (unmapped)", &line_str[location.range.clone()]),
+    };
+
+    format!(
+        "{}{}{}",
+        &line_str[..location.range.start],
+        remapped,
+        &line_str[location.range.end..]
+    )
+}
+
+/// Formats `original` as `file:line`, or `file:line:column` if the log stated a column.
+pub(crate) fn colon_location(original: &SourceLocation, column: Option<usize>) -> String {
+    match column {
+        Some(_) => format!(
+            "{}:{}:{}",
+            original.file,
+            original.line + 1,
+            original.column + 1
+        ),
+        None => format!("{}:{}", original.file, original.line + 1),
+    }
+}
+
+/// Parses `file_name:line` or `file_name:line:column` at byte `start` of `line_str`.
+pub(crate) fn parse_colon_location(
+    line_str: &str,
+    start: usize,
+    file_name: &str,
+) -> Option<LogLocation> {
+    let rest = line_str[start..]
+        .strip_prefix(file_name)?
+        .strip_prefix(':')?;
+    let (line, rest) = parse_number(rest)?;
+
+    let (column, rest) = match rest.strip_prefix(':').and_then(parse_number) {
+        Some((column, rest)) => (Some(column), rest),
+        None => (None, rest),
+    };
+
+    Some(LogLocation {
+        line,
+        column,
+        range: start..line_str.len() - rest.len(),
+    })
+}
+
+/// Parses a decimal number at the start of `s`, returning it and the rest of `s`.
+pub(crate) fn parse_number(s: &str) -> Option<(usize, &str)> {
+    let end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    Some((s[..end].parse().ok()?, &s[end..]))
+}
+
+/// Parses one of `severities`, e.g. `error:`, at the start of `s`, returning the severity
+/// and the message following it.
+pub(crate) fn parse_severity<'a>(
+    s: &'a str,
+    severities: &[(&str, Severity)],
+) -> Option<(Severity, &'a str)> {
+    severities.iter().find_map(|(prefix, severity)| {
+        s.strip_prefix(prefix)
+            .map(|message| (*severity, message.trim_start()))
+    })
+}
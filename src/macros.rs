@@ -0,0 +1,360 @@
+//! Object-like and function-like macro expansion, following the hide set algorithm
+//! from Dave Prosser's "C Preprocessing Algorithm".
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::rc::Rc;
+
+use crate::defines::MacroDefinition;
+use crate::pp_token::{tokenize, Token, TokenKind};
+
+type HideSet = Rc<HashSet<String>>;
+
+#[derive(Clone)]
+struct ExpToken {
+    token: Token,
+    hide_set: HideSet,
+}
+
+impl ExpToken {
+    fn is_whitespace(&self) -> bool {
+        self.token.kind == TokenKind::Whitespace
+    }
+}
+
+/// Error from `expand`, along with the index of the line on which it was encountered
+type ExpansionError = (usize, String);
+
+/// Expands all macros in `text`.
+///
+/// Newlines consumed by multi-line macro invocations are re-emitted after the expansion,
+/// so that subsequent lines keep their original numbering.
+#[cfg(feature = "macros")]
+pub(crate) fn expand_text(
+    text: &str,
+    defines: &HashMap<String, MacroDefinition>,
+) -> Result<String, ExpansionError> {
+    Ok(expand(wrap(tokenize(text)), defines)?
+        .into_iter()
+        .map(|t| t.token.text)
+        .collect())
+}
+
+/// Expands all macros in `tokens`.
+pub(crate) fn expand_tokens(
+    tokens: Vec<Token>,
+    defines: &HashMap<String, MacroDefinition>,
+) -> Result<Vec<Token>, String> {
+    Ok(expand(wrap(tokens), defines)
+        .map_err(|(_, message)| message)?
+        .into_iter()
+        .map(|t| t.token)
+        .collect())
+}
+
+fn wrap(tokens: Vec<Token>) -> Vec<ExpToken> {
+    let hide_set = HideSet::default();
+    tokens
+        .into_iter()
+        .map(|token| ExpToken {
+            token,
+            hide_set: hide_set.clone(),
+        })
+        .collect()
+}
+
+fn expand(
+    tokens: Vec<ExpToken>,
+    defines: &HashMap<String, MacroDefinition>,
+) -> Result<Vec<ExpToken>, ExpansionError> {
+    let mut input: VecDeque<ExpToken> = tokens.into();
+    let mut output: Vec<ExpToken> = Vec::new();
+    let mut line = 0;
+    let mut swallowed_newlines = 0;
+
+    while let Some(mut t) = input.pop_front() {
+        let definition = match defines.get(&t.token.text) {
+            Some(definition)
+                if t.token.kind == TokenKind::Ident && !t.hide_set.contains(&t.token.text) =>
+            {
+                definition
+            }
+            _ => {
+                let newlines = t.token.text.matches('\n').count();
+                if newlines > 0 {
+                    line += newlines;
+                    t.token.text.push_str(&"\n".repeat(swallowed_newlines));
+                    line += swallowed_newlines;
+                    swallowed_newlines = 0;
+                }
+
+                output.push(t);
+                continue;
+            }
+        };
+
+        let expansion = if let Some(params) = &definition.params {
+            let paren_pos = match input.iter().position(|t| !t.is_whitespace()) {
+                Some(pos) if input[pos].token.is_punct("(") => pos,
+                _ => {
+                    // Not an invocation; just the name of a function-like macro.
+                    output.push(t);
+                    continue;
+                }
+            };
+
+            for skipped in input.drain(..=paren_pos) {
+                swallowed_newlines += skipped.token.text.matches('\n').count();
+            }
+
+            let (args, rparen) =
+                collect_args(&mut input, &mut swallowed_newlines).ok_or_else(|| {
+                    (
+                        line,
+                        format!("unterminated invocation of macro {:?}", t.token.text),
+                    )
+                })?;
+            let args = match_args(params, args).ok_or_else(|| {
+                (
+                    line,
+                    format!("wrong number of arguments to macro {:?}", t.token.text),
+                )
+            })?;
+
+            let mut hide_set: HashSet<String> =
+                t.hide_set.intersection(&rparen.hide_set).cloned().collect();
+            hide_set.insert(t.token.text.clone());
+
+            substitute(definition, params, &args, &Rc::new(hide_set), defines)
+        } else {
+            let mut hide_set = (*t.hide_set).clone();
+            hide_set.insert(t.token.text.clone());
+
+            substitute(definition, &[], &[], &Rc::new(hide_set), defines)
+        }
+        .map_err(|message| (line, message))?;
+
+        // Rescan the expansion along with the rest of the input.
+        for t in expansion.into_iter().rev() {
+            input.push_front(t);
+        }
+    }
+
+    if swallowed_newlines > 0 {
+        output.push(ExpToken {
+            token: Token::new(TokenKind::Whitespace, "\n".repeat(swallowed_newlines)),
+            hide_set: HideSet::default(),
+        });
+    }
+
+    Ok(output)
+}
+
+/// Collects comma-separated arguments following the opening parenthesis of an invocation.
+///
+/// Newlines within the arguments are replaced by spaces, and counted in `swallowed_newlines`.
+fn collect_args(
+    input: &mut VecDeque<ExpToken>,
+    swallowed_newlines: &mut usize,
+) -> Option<(Vec<Vec<ExpToken>>, ExpToken)> {
+    let mut args = vec![Vec::new()];
+    let mut depth = 0;
+
+    loop {
+        let mut t = input.pop_front()?;
+
+        if t.token.is_punct("(") {
+            depth += 1;
+        } else if t.token.is_punct(")") {
+            if depth == 0 {
+                return Some((args, t));
+            }
+            depth -= 1;
+        } else if t.token.is_punct(",") && depth == 0 {
+            args.push(Vec::new());
+            continue;
+        } else if t.is_whitespace() {
+            let newlines = t.token.text.matches('\n').count();
+            if newlines > 0 {
+                *swallowed_newlines += newlines;
+                t.token.text = " ".to_string();
+            }
+        }
+
+        args.last_mut().unwrap().push(t);
+    }
+}
+
+/// Matches invocation arguments to `params`, folding any extra ones into `__VA_ARGS__`.
+/// Leading and trailing whitespace is removed from the arguments.
+fn match_args(params: &[String], args: Vec<Vec<ExpToken>>) -> Option<Vec<Vec<ExpToken>>> {
+    let mut args: Vec<Vec<ExpToken>> = args;
+
+    if params.is_empty() {
+        // `FOO()` passes a single empty argument.
+        return if args.len() == 1 && trim_whitespace(args.pop().unwrap()).is_empty() {
+            Some(Vec::new())
+        } else {
+            None
+        };
+    }
+
+    if params.last().map(String::as_str) == Some("...") {
+        if args.len() < params.len() - 1 {
+            return None;
+        }
+
+        let variadic: Vec<Vec<ExpToken>> =
+            args.drain((params.len() - 1).min(args.len())..).collect();
+        let mut joined = Vec::new();
+
+        for (i, arg) in variadic.into_iter().enumerate() {
+            if i > 0 {
+                joined.push(ExpToken {
+                    token: Token::new(TokenKind::Punct, ","),
+                    hide_set: HideSet::default(),
+                });
+            }
+            joined.extend(arg);
+        }
+
+        args.push(joined);
+        Some(args.into_iter().map(trim_whitespace).collect())
+    } else if args.len() == params.len() {
+        Some(args.into_iter().map(trim_whitespace).collect())
+    } else {
+        None
+    }
+}
+
+fn trim_whitespace(mut tokens: Vec<ExpToken>) -> Vec<ExpToken> {
+    while tokens.last().is_some_and(ExpToken::is_whitespace) {
+        tokens.pop();
+    }
+
+    let leading = tokens.iter().take_while(|t| t.is_whitespace()).count();
+    tokens.drain(..leading);
+    tokens
+}
+
+fn param_index(params: &[String], t: &Token) -> Option<usize> {
+    if t.kind != TokenKind::Ident {
+        return None;
+    }
+
+    params
+        .iter()
+        .position(|p| *p == t.text || (p == "..." && t.text == "__VA_ARGS__"))
+}
+
+/// Builds the replacement list of a macro invocation, with parameters substituted,
+/// `#` and `##` applied, and `hide_set` added to every resulting token.
+fn substitute(
+    definition: &MacroDefinition,
+    params: &[String],
+    args: &[Vec<ExpToken>],
+    hide_set: &HideSet,
+    defines: &HashMap<String, MacroDefinition>,
+) -> Result<Vec<ExpToken>, String> {
+    let body = wrap(tokenize(&definition.body));
+    let next_non_whitespace = |from: usize| (from..body.len()).find(|&i| !body[i].is_whitespace());
+
+    let mut result: Vec<ExpToken> = Vec::new();
+    let mut i = 0;
+
+    while i < body.len() {
+        let t = &body[i].token;
+
+        if t.is_punct("#") && definition.params.is_some() {
+            let arg = next_non_whitespace(i + 1)
+                .and_then(|j| Some((j, param_index(params, &body[j].token)?)))
+                .ok_or_else(|| "'#' is not followed by a macro parameter".to_string())?;
+
+            result.push(ExpToken {
+                token: stringize(&args[arg.1]),
+                hide_set: HideSet::default(),
+            });
+            i = arg.0 + 1;
+        } else if t.is_punct("##") {
+            let j = next_non_whitespace(i + 1)
+                .filter(|_| body[..i].iter().any(|t| !t.is_whitespace()))
+                .ok_or_else(|| {
+                    "'##' cannot appear at either end of a macro expansion".to_string()
+                })?;
+
+            let rhs = match param_index(params, &body[j].token) {
+                Some(arg) => args[arg].clone(),
+                None => vec![body[j].clone()],
+            };
+
+            paste(&mut result, rhs);
+            i = j + 1;
+        } else if let Some(arg) = param_index(params, t) {
+            let followed_by_paste =
+                next_non_whitespace(i + 1).is_some_and(|j| body[j].token.is_punct("##"));
+
+            if followed_by_paste {
+                result.extend(args[arg].iter().cloned());
+            } else {
+                result.extend(expand(args[arg].clone(), defines).map_err(|(_, message)| message)?);
+            }
+            i += 1;
+        } else {
+            result.push(body[i].clone());
+            i += 1;
+        }
+    }
+
+    for t in &mut result {
+        if !t.hide_set.is_empty() || !hide_set.is_empty() {
+            t.hide_set = Rc::new(t.hide_set.union(hide_set).cloned().collect());
+        }
+    }
+
+    Ok(result)
+}
+
+/// Concatenates the last token of `lhs` with the first one of `rhs`.
+fn paste(lhs: &mut Vec<ExpToken>, rhs: Vec<ExpToken>) {
+    while lhs.last().is_some_and(ExpToken::is_whitespace) {
+        lhs.pop();
+    }
+
+    let mut rhs = rhs.into_iter();
+
+    let (last, first) = match (lhs.pop(), rhs.next()) {
+        (Some(last), Some(first)) => (last, first),
+        (last, first) => {
+            // One side is an empty argument; nothing to paste.
+            lhs.extend(last.into_iter().chain(first));
+            lhs.extend(rhs);
+            return;
+        }
+    };
+
+    let pasted = format!("{}{}", last.token.text, first.token.text);
+    lhs.extend(tokenize(&pasted).into_iter().map(|token| ExpToken {
+        token,
+        hide_set: last.hide_set.clone(),
+    }));
+    lhs.extend(rhs);
+}
+
+fn stringize(arg: &[ExpToken]) -> Token {
+    let mut s = String::from("\"");
+
+    for t in arg {
+        if t.is_whitespace() {
+            s.push(' ');
+        } else {
+            for c in t.token.text.chars() {
+                if c == '"' || c == '\\' {
+                    s.push('\\');
+                }
+                s.push(c);
+            }
+        }
+    }
+
+    s.push('"');
+    Token::new(TokenKind::Other, s)
+}
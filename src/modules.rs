@@ -0,0 +1,326 @@
+//! Module imports in the style of naga_oil: `#import some::module` and
+//! `#import some::module::{a, b}`.
+//!
+//! A module is a file found through the `IncludeProvider`, and included once per root.
+//! Its top-level declarations are renamed to `some_module__name`, and so are references to them
+//! in the importing files, written either as `module::name` or `some::module::name`,
+//! or as plain `name` if listed in braces after the module path.
+//!
+//! Renaming works on tokens of the scanned sources, which have comments blanked out already.
+//! Declarations are recognized heuristically: in WGSL, as names following `fn`, `struct`,
+//! `const`, `var`, `override` or `alias` at the top level; in GLSL and HLSL, as top-level
+//! names followed by one of `( [ { = ; , :`. Interface declarations, i.e. ones qualified with
+//! `in`, `out`, `uniform`, `buffer`, `layout(...)` and the like, including block and instance
+//! names, keep their names, since other shader stages and the host refer to them.
+//!
+//! Renamed identifiers change length, so columns past them on the same line no longer match
+//! the original files. Lines are unaffected.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::defines::split_ident;
+use crate::pp_token::{tokenize, Token, TokenKind};
+use crate::{Dialect, SourceChunk};
+
+/// Arguments of an `#import` directive naming a module
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Import {
+    /// Path of the module, e.g. `["some", "module"]`
+    pub module: Vec<String>,
+
+    /// Names to be usable unqualified, listed in braces after the module path
+    pub items: Vec<String>,
+}
+
+/// An `#import` processed while scanning
+pub(crate) struct ImportedModule {
+    /// File containing the directive
+    pub importer: String,
+
+    pub import: Import,
+
+    /// Resolved path of the module's file
+    pub file: String,
+}
+
+/// Parses the part of an `#import` directive following the directive name.
+pub(crate) fn parse_import(s: &str) -> Option<Import> {
+    let s = s.trim();
+
+    let (module, items) = match s.find('{') {
+        Some(brace) => {
+            let module = s[..brace].trim_end().strip_suffix("::")?;
+            let items = s[brace + 1..].strip_suffix('}')?;
+
+            let items = items
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(parse_name)
+                .collect::<Option<Vec<_>>>()?;
+
+            (module, items)
+        }
+        None => (s, Vec::new()),
+    };
+
+    let module = module
+        .split("::")
+        .map(|segment| parse_name(segment.trim()))
+        .collect::<Option<Vec<_>>>()?;
+
+    Some(Import { module, items })
+}
+
+fn parse_name(s: &str) -> Option<String> {
+    match split_ident(s) {
+        Some((name, "")) => Some(name.to_owned()),
+        _ => None,
+    }
+}
+
+/// Whether the logical line of an `#import` directive names a file rather than a module.
+pub(crate) fn is_file_import(logical: &str) -> bool {
+    split_ident(logical).is_some_and(|(_, args)| args.trim_start().starts_with(['"', '<']))
+}
+
+/// Path through which the file of `module` is requested from the `IncludeProvider`,
+/// e.g. `some/module.wgsl`.
+pub(crate) fn module_file_path(module: &[String], dialect: Dialect) -> String {
+    let extension = match dialect {
+        Dialect::Glsl => "glsl",
+        Dialect::Hlsl => "hlsl",
+        Dialect::Wgsl => "wgsl",
+    };
+
+    format!("{}.{}", module.join("/"), extension)
+}
+
+/// Name under which `name` declared in `module` ends up in the output.
+pub(crate) fn mangle(module: &[String], name: &str) -> String {
+    format!("{}__{}", module.join("_"), name)
+}
+
+/// Renames the declarations of imported modules, and references to them, in `chunks`.
+pub(crate) fn mangle_chunks<IncludeContext>(
+    chunks: &mut [SourceChunk<IncludeContext>],
+    imports: &[ImportedModule],
+    dialect: Dialect,
+) {
+    if imports.is_empty() {
+        return;
+    }
+
+    let mut declared: HashMap<&str, HashSet<String>> = HashMap::new();
+    for imported in imports {
+        declared.entry(&imported.file).or_insert_with(|| {
+            let source: Vec<&str> = chunks
+                .iter()
+                .filter(|chunk| chunk.file == imported.file)
+                .map(|chunk| chunk.source.as_str())
+                .collect();
+
+            declared_names(&source.join("\n"), dialect)
+        });
+    }
+
+    let mut renames: HashMap<&str, HashMap<String, String>> = HashMap::new();
+    for imported in imports {
+        let module = &imported.import.module;
+        let names = &declared[imported.file.as_str()];
+
+        let module_renames = renames.entry(&imported.file).or_default();
+        for name in names {
+            module_renames.insert(name.clone(), mangle(module, name));
+        }
+
+        let importer_renames = renames.entry(&imported.importer).or_default();
+        for name in names {
+            let mangled = mangle(module, name);
+            importer_renames.insert(format!("{}::{}", module.join("::"), name), mangled.clone());
+            if let Some(last) = module.last() {
+                importer_renames.insert(format!("{}::{}", last, name), mangled);
+            }
+        }
+        for item in &imported.import.items {
+            importer_renames.insert(item.clone(), mangle(module, item));
+        }
+    }
+
+    for chunk in chunks.iter_mut() {
+        if let Some(renames) = renames.get(chunk.file.as_str()) {
+            chunk.source = rename(&chunk.source, renames);
+        }
+    }
+}
+
+/// Qualifiers of GLSL and HLSL declarations which are linked by name across shader stages,
+/// or bound by the host
+const INTERFACE_QUALIFIERS: &[&str] = &[
+    "in",
+    "out",
+    "inout",
+    "uniform",
+    "buffer",
+    "attribute",
+    "varying",
+    "layout",
+    "cbuffer",
+    "tbuffer",
+];
+
+/// Top-level declarations in `source`.
+fn declared_names(source: &str, dialect: Dialect) -> HashSet<String> {
+    // Directives aren't code.
+    let source: String = source
+        .split_inclusive('\n')
+        .map(|line| {
+            if line.trim_start().starts_with('#') {
+                "\n"
+            } else {
+                line
+            }
+        })
+        .collect();
+
+    let tokens: Vec<Token> = tokenize(&source)
+        .into_iter()
+        .filter(|token| token.kind != TokenKind::Whitespace)
+        .collect();
+
+    let mut names = HashSet::new();
+    let mut depth = 0;
+
+    // Whether the rest of the statement, e.g. an initializer, holds no declarations
+    let mut skipping = false;
+
+    // Whether the current statement declares part of the shader's interface
+    let mut interface = false;
+
+    for (i, token) in tokens.iter().enumerate() {
+        let prev = i.checked_sub(1).map(|i| &tokens[i]);
+        let next = tokens.get(i + 1);
+
+        match token.text.as_str() {
+            "(" | "[" | "{" => depth += 1,
+            ")" | "]" | "}" => depth -= 1,
+            _ => {}
+        }
+
+        if depth != 0 {
+            continue;
+        }
+
+        match dialect {
+            Dialect::Wgsl => {
+                if !matches!(
+                    token.text.as_str(),
+                    "fn" | "struct" | "const" | "var" | "override" | "alias"
+                ) {
+                    continue;
+                }
+
+                // Skip the parameters of e.g. `var<storage, read> name`.
+                let mut name_idx = i + 1;
+                if tokens.get(name_idx).is_some_and(|t| t.is_punct("<")) {
+                    name_idx += tokens[name_idx..]
+                        .iter()
+                        .position(|t| t.is_punct(">"))
+                        .map_or(tokens.len(), |len| len + 1);
+                }
+
+                if let Some(name) = tokens.get(name_idx) {
+                    if name.kind == TokenKind::Ident {
+                        names.insert(name.text.clone());
+                    }
+                }
+            }
+            Dialect::Glsl | Dialect::Hlsl => {
+                if token.is_punct(";") || token.is_punct(",") {
+                    skipping = false;
+                } else if token.is_punct("=") || token.text == "precision" {
+                    skipping = true;
+                }
+
+                if token.is_punct(";") {
+                    interface = false;
+                } else if INTERFACE_QUALIFIERS.contains(&token.text.as_str()) {
+                    interface = true;
+                }
+
+                let is_declaration = token.kind == TokenKind::Ident
+                    && !skipping
+                    && !interface
+                    && !token.text.starts_with("gl_")
+                    && !prev.is_some_and(|prev| prev.is_punct(":") || prev.is_punct("."))
+                    && next.is_some_and(|next| {
+                        matches!(next.text.as_str(), "(" | "[" | "{" | "=" | ";" | "," | ":")
+                    });
+
+                if is_declaration {
+                    names.insert(token.text.clone());
+                }
+            }
+        }
+    }
+
+    names
+}
+
+/// Replaces identifiers, and `::`-qualified paths, found in `renames`.
+/// Members, such as `x` in `v.x`, are left alone.
+fn rename(source: &str, renames: &HashMap<String, String>) -> String {
+    let tokens = tokenize(source);
+    let mut result = String::with_capacity(source.len());
+    let mut i = 0;
+
+    while i < tokens.len() {
+        let token = &tokens[i];
+
+        let mut prev = tokens[..i]
+            .iter()
+            .rev()
+            .filter(|token| token.kind != TokenKind::Whitespace);
+        let is_member = match (prev.next(), prev.next()) {
+            (Some(p), _) if p.is_punct(".") => true,
+            (Some(p0), Some(p1)) => p0.is_punct(":") && p1.is_punct(":"),
+            _ => false,
+        };
+
+        if token.kind != TokenKind::Ident || is_member {
+            result.push_str(&token.text);
+            i += 1;
+            continue;
+        }
+
+        // Longest `a::b::c` path starting here
+        let mut paths = vec![(token.text.clone(), i + 1)];
+        let mut end = i + 1;
+        while end + 2 < tokens.len()
+            && tokens[end].is_punct(":")
+            && tokens[end + 1].is_punct(":")
+            && tokens[end + 2].kind == TokenKind::Ident
+        {
+            end += 3;
+            let path = format!("{}::{}", paths.last().unwrap().0, tokens[end - 1].text);
+            paths.push((path, end));
+        }
+
+        match paths
+            .iter()
+            .rev()
+            .find_map(|(path, end)| Some((renames.get(path)?, *end)))
+        {
+            Some((renamed, end)) => {
+                result.push_str(renamed);
+                i = end;
+            }
+            None => {
+                result.push_str(&token.text);
+                i += 1;
+            }
+        }
+    }
+
+    result
+}
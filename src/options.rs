@@ -0,0 +1,75 @@
+/// Name used as [`SourceChunk::file`](crate::SourceChunk::file) for the chunk containing
+/// [`ProcessOptions::defines`].
+pub const DEFINES_PRELUDE_FILE: &str = "<defines>";
+
+/// Shading language of the processed files
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Dialect {
+    #[default]
+    Glsl,
+
+    /// HLSL, as compiled by DXC or FXC. Backslashes in `#include` paths are taken literally,
+    /// as in Windows paths. See also [`HlslIncludeProvider`](crate::HlslIncludeProvider).
+    Hlsl,
+
+    /// WGSL. Block comments nest, there are no line continuations nor escapes in `#include`
+    /// paths, and `#import "file"` is accepted as a synonym of `#include "file"`, since WGSL
+    /// has no preprocessor of its own.
+    Wgsl,
+}
+
+/// Options controlling how [`process_file_with_options`](crate::process_file_with_options)
+/// crawls the sources.
+#[derive(Clone, Debug, Default)]
+pub struct ProcessOptions {
+    /// Evaluate conditional directives (`#if`, `#ifdef`, `#ifndef`, `#elif`, `#else`, `#endif`)
+    /// while crawling, and skip `#include` directives in inactive branches.
+    ///
+    /// `#define` and `#undef` are tracked in order to evaluate the conditions.
+    /// All of these directives are still copied into the output, so that the shader compiler
+    /// can see the same structure.
+    ///
+    /// When disabled, every `#include` is followed regardless of the surrounding conditionals.
+    pub evaluate_conditionals: bool,
+
+    /// Replace code in inactive branches with blank lines. Only used with `evaluate_conditionals`.
+    pub strip_inactive_code: bool,
+
+    /// Macros to define before processing the root file, as `(name, value)` pairs.
+    /// `name` may contain a parameter list, e.g. `("SQR(x)", "((x) * (x))")`.
+    ///
+    /// The corresponding `#define` directives are emitted as a separate first chunk,
+    /// with `file` set to [`DEFINES_PRELUDE_FILE`], so that the root file's chunks
+    /// keep their original line offsets. They are also used when evaluating conditionals.
+    /// If the root file starts with a `#version` directive, the chunk follows it instead.
+    pub defines: Vec<(String, String)>,
+
+    /// Move `#extension` directives from all files to the start of the output, right after
+    /// `#version`, since GLSL requires them to precede any non-preprocessor tokens.
+    ///
+    /// Each directive becomes a separate chunk pointing back at its origin, and leaves
+    /// a blank line behind. Directives within conditionals are left in place, and so are lines
+    /// continuing a previous one, such as in the body of a multi-line `#define`.
+    pub hoist_extensions: bool,
+
+    /// Language of the processed files
+    pub dialect: Dialect,
+
+    /// Expand object-like and function-like macros, including `#` stringification
+    /// and `##` pasting, so that the output contains fully preprocessed code.
+    ///
+    /// Implies `evaluate_conditionals` and `strip_inactive_code`. `#define`, `#undef` and
+    /// conditional directives are consumed rather than copied into the output,
+    /// and so are `defines`. Other directives, such as `#version`, are copied verbatim.
+    ///
+    /// Requires the `macros` feature; ignored without it.
+    pub expand_macros: bool,
+
+    /// Bring in modules via `#import some::module` and `#import some::module::{a, b}`,
+    /// in the style of naga_oil, renaming their top-level declarations.
+    /// See the [crate documentation](crate) for details.
+    ///
+    /// When disabled, these directives and `#define_import_path` are copied verbatim,
+    /// like other unrecognized directives. WGSL's `#import "file"` is an include either way.
+    pub module_imports: bool,
+}
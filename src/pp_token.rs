@@ -0,0 +1,99 @@
+//! Minimal preprocessing tokenizer used for directive arguments.
+//!
+//! Comments are not recognized here; the scanner blanks them out before handing text over.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum TokenKind {
+    Ident,
+    Number,
+    Punct,
+    Whitespace,
+    Other,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Token {
+    pub kind: TokenKind,
+    pub text: String,
+}
+
+impl Token {
+    pub fn new(kind: TokenKind, text: impl Into<String>) -> Self {
+        Self {
+            kind,
+            text: text.into(),
+        }
+    }
+
+    pub fn is_punct(&self, p: &str) -> bool {
+        self.kind == TokenKind::Punct && self.text == p
+    }
+}
+
+// Longest first, so that the greedy match below picks e.g. `<<=` over `<<`.
+const MULTI_CHAR_PUNCTUATORS: &[&str] = &[
+    "<<=", ">>=", "...", "##", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "++", "--", "+=",
+    "-=", "*=", "/=", "%=", "&=", "|=", "^=", "->",
+];
+
+pub(crate) fn is_ident_start(c: char) -> bool {
+    c == '_' || c.is_alphabetic()
+}
+
+pub(crate) fn is_ident_continue(c: char) -> bool {
+    c == '_' || c.is_alphanumeric()
+}
+
+pub(crate) fn tokenize(s: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut rest = s;
+
+    while let Some(c) = rest.chars().next() {
+        let (kind, len) = if c.is_whitespace() {
+            (
+                TokenKind::Whitespace,
+                rest.find(|c: char| !c.is_whitespace())
+                    .unwrap_or(rest.len()),
+            )
+        } else if is_ident_start(c) {
+            (
+                TokenKind::Ident,
+                rest.find(|c: char| !is_ident_continue(c))
+                    .unwrap_or(rest.len()),
+            )
+        } else if c.is_ascii_digit()
+            || (c == '.' && rest[1..].starts_with(|c: char| c.is_ascii_digit()))
+        {
+            (TokenKind::Number, pp_number_len(rest))
+        } else if let Some(p) = MULTI_CHAR_PUNCTUATORS.iter().find(|p| rest.starts_with(*p)) {
+            (TokenKind::Punct, p.len())
+        } else if c.is_ascii_punctuation() {
+            (TokenKind::Punct, 1)
+        } else {
+            (TokenKind::Other, c.len_utf8())
+        };
+
+        tokens.push(Token::new(kind, &rest[..len]));
+        rest = &rest[len..];
+    }
+
+    tokens
+}
+
+fn pp_number_len(s: &str) -> usize {
+    let mut prev = '\0';
+
+    for (i, c) in s.char_indices() {
+        let continues = is_ident_continue(c)
+            || c == '.'
+            || ((c == '+' || c == '-') && matches!(prev, 'e' | 'E' | 'p' | 'P'));
+
+        if !continues {
+            return i;
+        }
+
+        prev = c;
+    }
+
+    s.len()
+}
@@ -0,0 +1,108 @@
+use crate::defines::split_ident;
+use crate::line_directives::split_version_directive;
+use crate::SourceChunk;
+
+/// Places `prelude` at the start of `chunks`, but after the `#version` directive if the first
+/// chunk starts with one. With `hoist_extensions`, `#extension` directives outside of any
+/// conditional or line continuation are moved in between, each as a separate chunk pointing
+/// back at its origin; blank lines are left in their place.
+pub(crate) fn insert_preamble<IncludeContext: Clone>(
+    chunks: &mut Vec<SourceChunk<IncludeContext>>,
+    prelude: Option<SourceChunk<IncludeContext>>,
+    hoist_extensions: bool,
+) {
+    let mut version = None;
+    let mut preamble = Vec::new();
+
+    if let Some(first) = chunks.first_mut() {
+        let (version_source, rest) = split_version_directive(&first.source);
+
+        if !version_source.is_empty() {
+            let rest = rest.to_owned();
+            let version_lines = version_source.matches('\n').count();
+
+            version = Some(SourceChunk {
+                source: version_source.to_owned(),
+                context: first.context.clone(),
+                file: first.file.clone(),
+                line_offset: first.line_offset,
+                column_offset: first.column_offset,
+            });
+
+            first.source = rest;
+            first.line_offset += version_lines;
+            first.column_offset = 0;
+
+            if first.source.is_empty() {
+                chunks.remove(0);
+            }
+        }
+    }
+
+    if hoist_extensions {
+        // Nesting depth of conditional directives; extensions within them stay in place
+        let mut depth = 0usize;
+
+        for chunk in chunks.iter_mut() {
+            let mut source = String::with_capacity(chunk.source.len());
+
+            // Whether the previous line ends with a line continuation
+            let mut continued = false;
+
+            for (i, line) in chunk.source.split_inclusive('\n').enumerate() {
+                let continuation = continued;
+                continued = line.trim_end_matches(['\r', '\n']).ends_with('\\');
+
+                // Continuations of e.g. a `#define` aren't directives of their own.
+                let directive = if continuation {
+                    None
+                } else {
+                    line.trim_start()
+                        .strip_prefix('#')
+                        .and_then(split_ident)
+                        .map(|(name, _)| name)
+                };
+
+                match directive {
+                    Some("if" | "ifdef" | "ifndef") => depth += 1,
+                    Some("endif") => depth = depth.saturating_sub(1),
+                    _ => {}
+                }
+
+                if directive == Some("extension") && depth == 0 && !continued {
+                    let directive = line.trim_end_matches('\n');
+
+                    preamble.push(SourceChunk {
+                        source: format!("{}\n", directive),
+                        context: chunk.context.clone(),
+                        file: chunk.file.clone(),
+                        line_offset: chunk.line_offset + i,
+                        column_offset: if i == 0 { chunk.column_offset } else { 0 },
+                    });
+
+                    source.push_str(&line[directive.len()..]);
+                } else {
+                    source.push_str(line);
+                }
+            }
+
+            chunk.source = source;
+        }
+
+        chunks.retain(|chunk| !chunk.source.is_empty());
+    }
+
+    preamble.extend(prelude);
+
+    if let Some(mut version) = version {
+        // Whatever follows needs to start on a line of its own.
+        let followed = !preamble.is_empty() || !chunks.is_empty();
+        if followed && !version.source.ends_with('\n') {
+            version.source.push('\n');
+        }
+
+        preamble.insert(0, version);
+    }
+
+    chunks.splice(0..0, preamble);
+}
@@ -0,0 +1,61 @@
+use crate::scanner::ScannerState;
+use crate::{IncludeProvider, PrepperError, ProcessOptions, ProcessOutput};
+
+/// Session for processing many root files with the same options,
+/// reusing the results of scanning files shared between them.
+///
+/// Each file's scan (its code, and the positions of its directives) is cached by resolved path
+/// and content hash. Later roots including the same file replay the cached result, resolving
+/// and processing its includes again, but without re-scanning it. Conditional directives and
+/// macro definitions are evaluated again when replaying, so the cache also serves permutations
+/// of the same shader built with different defines.
+///
+/// Files are scanned every time when expanding macros, and when they contain a module `#import`,
+/// `#pragma` within a conditional, or a malformed `#include` in an inactive branch.
+///
+/// File contents still come from the `IncludeProvider`; combine with
+/// [`CachingIncludeProvider`](crate::CachingIncludeProvider) to also avoid reading them again.
+pub struct Preprocessor {
+    options: ProcessOptions,
+    scan_cache: crate::scanner::ScanCache,
+}
+
+impl Preprocessor {
+    pub fn new(options: ProcessOptions) -> Self {
+        Self {
+            options,
+            scan_cache: Default::default(),
+        }
+    }
+
+    pub fn options(&self) -> &ProcessOptions {
+        &self.options
+    }
+
+    /// Like [`process_file_with_output`](crate::process_file_with_output),
+    /// with the options of this session.
+    pub fn process_file<IncludeContext: Clone>(
+        &mut self,
+        file_path: &str,
+        include_provider: &mut dyn IncludeProvider<IncludeContext = IncludeContext>,
+        include_context: IncludeContext,
+    ) -> Result<ProcessOutput<IncludeContext>, PrepperError> {
+        let mut state = ScannerState::new(self.options.clone());
+        state.scan_cache = Some(std::mem::take(&mut self.scan_cache));
+
+        let result = crate::process_file_with_state(
+            file_path,
+            include_provider,
+            include_context,
+            &mut state,
+        );
+
+        self.scan_cache = state.scan_cache.take().unwrap_or_default();
+        result
+    }
+
+    /// Forget all cached scans.
+    pub fn clear_cache(&mut self) {
+        self.scan_cache.clear();
+    }
+}
@@ -0,0 +1,49 @@
+use crate::{IncludeGraph, ResolvedIncludePath, SourceChunk};
+
+/// Result of [`process_file_with_output`](crate::process_file_with_output)
+#[derive(Debug)]
+pub struct ProcessOutput<IncludeContext> {
+    /// The same chunks as returned by [`process_file_with_options`](crate::process_file_with_options)
+    pub chunks: Vec<SourceChunk<IncludeContext>>,
+
+    /// Every file the crawl depended on, starting with the root file, in the order first encountered.
+    ///
+    /// Includes files which were resolved but then skipped, e.g. due to `#pragma once`
+    /// or an include guard, since changing them can still change the output.
+    pub dependencies: Vec<ResolvedIncludePath>,
+
+    /// The `#include` directives between [`dependencies`](Self::dependencies)
+    pub include_graph: IncludeGraph,
+}
+
+impl<IncludeContext> ProcessOutput<IncludeContext> {
+    /// Format [`dependencies`](Self::dependencies) as a Makefile rule for `target`,
+    /// in the format of GCC's `-MD` depfiles, as understood by e.g. make and ninja.
+    pub fn to_depfile(&self, target: &str) -> String {
+        let mut result = escape_depfile_path(target);
+        result.push(':');
+
+        for dependency in &self.dependencies {
+            result.push_str(" \\\n  ");
+            result.push_str(&escape_depfile_path(&dependency.0));
+        }
+
+        result.push('\n');
+        result
+    }
+}
+
+fn escape_depfile_path(path: &str) -> String {
+    let mut result = String::with_capacity(path.len());
+
+    for c in path.chars() {
+        match c {
+            ' ' => result.push_str("\\ "),
+            '#' => result.push_str("\\#"),
+            '$' => result.push_str("$$"),
+            c => result.push(c),
+        }
+    }
+
+    result
+}
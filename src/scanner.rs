@@ -1,30 +1,60 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::iter::Peekable;
 use std::str::Chars;
+use std::sync::Arc;
 
-use crate::{IncludeProvider, PrepperError, SourceChunk};
+use crate::defines::{parse_define, split_ident, MacroDefinition};
+use crate::modules::{self, ImportedModule};
+use crate::{
+    expression, Dialect, IncludeEdge, IncludeGraph, IncludeKind, IncludeProvider,
+    IncludeStackEntry, PrepperError, ProcessOptions, ResolvedIncludePath, SourceChunk,
+};
+
+/// Position of a character in the input. Lines and columns are one-based, and columns count characters.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Location {
+    line: u32,
+    column: u32,
+    byte_offset: usize,
+}
+
+impl Location {
+    const START: Location = Location {
+        line: 1,
+        column: 1,
+        byte_offset: 0,
+    };
+
+    fn advance(&mut self, c: char) {
+        self.byte_offset += c.len_utf8();
+
+        // Possible undefined overflow.
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+    }
+}
 
 #[derive(Clone)]
 struct LocationTracking<I> {
     iter: I,
-    line: u32,
+    location: Location,
 }
 
 impl<I> Iterator for LocationTracking<I>
 where
     I: Iterator<Item = char>,
 {
-    type Item = (u32, <I as Iterator>::Item);
+    type Item = (Location, <I as Iterator>::Item);
 
     #[inline]
-    fn next(&mut self) -> Option<(u32, <I as Iterator>::Item)> {
+    fn next(&mut self) -> Option<(Location, <I as Iterator>::Item)> {
         self.iter.next().map(|a| {
-            let nl = a == '\n';
-            let ret = (self.line, a);
-            // Possible undefined overflow.
-            if nl {
-                self.line += 1;
-            }
+            let ret = (self.location, a);
+            self.location.advance(a);
             ret
         })
     }
@@ -32,17 +62,220 @@ where
 
 type ResolvedPathString = String;
 
+/// State shared by the scanners of all files visited while processing a single root file
+#[derive(Default)]
+pub struct ScannerState {
+    pub options: ProcessOptions,
+    pub skip_includes: HashSet<ResolvedPathString>,
+    pub defines: HashMap<String, MacroDefinition>,
+
+    /// Files wrapped in a classic `#ifndef` include guard, along with the guard macro name
+    pub include_guards: HashMap<ResolvedPathString, String>,
+
+    /// Without conditional evaluation: files in `include_guards` whose guard can't be relied upon,
+    /// since they were last scanned within an `#if` group, or the guard macro was `#undef`'d since
+    pub unreliable_include_guards: HashSet<ResolvedPathString>,
+
+    /// Without conditional evaluation: number of `#if` groups open around the file being scanned,
+    /// in the files including it. The groups of their own include guards aren't counted.
+    pub enclosing_conditionals: u32,
+
+    /// Files currently being scanned, with the line of the `#include` being processed in each
+    pub include_stack: Vec<IncludeStackEntry>,
+
+    /// Every file resolved while crawling, including ones which were then skipped,
+    /// and the `#include` directives between them
+    pub include_graph: IncludeGraph,
+
+    /// Scan results reusable across roots; `None` unless processing in a `Preprocessor` session
+    pub scan_cache: Option<ScanCache>,
+
+    /// Modules imported via `#import`, in order
+    pub imports: Vec<ImportedModule>,
+}
+
+/// Scan results of files, keyed by resolved path and content hash
+pub type ScanCache = HashMap<(ResolvedPathString, u64), Arc<CachedScan>>;
+
+/// Outcome of scanning a file which doesn't depend on any state shared between files,
+/// and can thus be replayed in place of scanning the file again
+pub struct CachedScan {
+    segments: Vec<ScanSegment>,
+    include_guard: Option<String>,
+}
+
+/// Step of a scan, as needed for replaying it. Conditional directives and macro definitions
+/// are evaluated again when replaying, so that a scan is reusable whatever the defines.
+enum ScanSegment {
+    /// Code, as passed to `emit`
+    Text(String),
+
+    /// Directive copied into the output without interpretation
+    Directive(String),
+
+    /// Conditional directive, as returned by `read_directive_line`
+    Conditional {
+        line: u32,
+        output: String,
+        logical: String,
+    },
+
+    /// `#define` or `#undef`, as returned by `read_directive_line`
+    Define {
+        line: u32,
+        output: String,
+        logical: String,
+    },
+
+    /// `#include`, processed only if active; the current chunk resumes at `resume` after it
+    Include {
+        path: String,
+        kind: IncludeKind,
+        line: u32,
+        resume: Location,
+    },
+
+    PragmaOnce,
+
+    /// The include guard is defined, and registered ahead of the end of the file
+    IncludeGuard(String),
+}
+
+impl ScannerState {
+    pub fn new(options: ProcessOptions) -> Self {
+        Self {
+            options,
+            ..Default::default()
+        }
+    }
+}
+
+/// Detection of the `#ifndef GUARD / #define GUARD / ... / #endif` pattern around a whole file
+enum IncludeGuardDetection {
+    /// Nothing but whitespace and comments seen yet
+    Start,
+
+    /// Inside the `#ifndef` opening the file
+    Open {
+        name: String,
+        defined: bool,
+        depth: u32,
+    },
+
+    /// The opening `#ifndef` has been closed; only whitespace and comments may follow
+    Closed { name: String },
+
+    /// The file doesn't follow the pattern
+    NotGuarded,
+}
+
+impl IncludeGuardDetection {
+    fn on_token(&mut self) {
+        if !matches!(self, IncludeGuardDetection::Open { .. }) {
+            *self = IncludeGuardDetection::NotGuarded;
+        }
+    }
+
+    fn on_directive(&mut self, directive: &str, args: &str) {
+        match self {
+            IncludeGuardDetection::Start => {
+                *self = match guard_condition_macro(directive, args) {
+                    Some(name) => IncludeGuardDetection::Open {
+                        name: name.to_string(),
+                        defined: false,
+                        depth: 1,
+                    },
+                    None => IncludeGuardDetection::NotGuarded,
+                };
+            }
+            IncludeGuardDetection::Open {
+                name,
+                defined,
+                depth,
+            } => match directive {
+                "if" | "ifdef" | "ifndef" => *depth += 1,
+                "else" | "elif" if *depth == 1 => *self = IncludeGuardDetection::NotGuarded,
+                "endif" if *depth == 1 => {
+                    *self = if *defined {
+                        IncludeGuardDetection::Closed {
+                            name: std::mem::take(name),
+                        }
+                    } else {
+                        IncludeGuardDetection::NotGuarded
+                    };
+                }
+                "endif" => *depth -= 1,
+                "define" if *depth == 1 => {
+                    *defined |= split_ident(args).map(|(ident, _)| ident) == Some(name.as_str());
+                }
+                _ => {}
+            },
+            _ => *self = IncludeGuardDetection::NotGuarded,
+        }
+    }
+}
+
+/// Macro name tested by `#ifndef NAME` or `#if !defined(NAME)`.
+fn guard_condition_macro<'a>(directive: &str, args: &'a str) -> Option<&'a str> {
+    let (name, rest) = match directive {
+        "ifndef" => split_ident(args)?,
+        "if" => {
+            let args = args.trim_start().strip_prefix('!')?;
+
+            match split_ident(args)? {
+                ("defined", rest) => match rest.trim_start().strip_prefix('(') {
+                    Some(rest) => {
+                        let (name, rest) = split_ident(rest)?;
+                        (name, rest.trim_start().strip_prefix(')')?)
+                    }
+                    None => split_ident(rest)?,
+                },
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+
+    if rest.trim().is_empty() {
+        Some(name)
+    } else {
+        None
+    }
+}
+
+/// One level of `#if` ... `#endif` nesting
+struct ConditionalGroup {
+    /// Line of the opening directive
+    line: u32,
+    parent_active: bool,
+    branch_taken: bool,
+    active: bool,
+    seen_else: bool,
+}
+
 // Inspired by JayKickliter/monkey
 pub struct Scanner<'input, 'provider, 'state, IncludeContext> {
     include_provider: &'provider mut dyn IncludeProvider<IncludeContext = IncludeContext>,
     include_context: IncludeContext,
+    input: &'input str,
     input_iter: Peekable<LocationTracking<Chars<'input>>>,
     this_file: ResolvedPathString,
-    prior_includes: &'state mut HashSet<ResolvedPathString>,
-    skip_includes: &'state mut HashSet<ResolvedPathString>,
+    state: &'state mut ScannerState,
+    conditionals: Vec<ConditionalGroup>,
+
+    /// Without conditional evaluation: number of `#if` groups currently open in this file
+    unevaluated_conditionals: u32,
+
+    include_guard: IncludeGuardDetection,
     chunks: Vec<SourceChunk<IncludeContext>>,
     current_chunk: String,
-    current_chunk_first_line: u32,
+    current_chunk_start: Location,
+
+    /// Text which still needs macro expansion before it can be appended to `current_chunk`
+    pending_text: String,
+
+    /// Everything output so far, if recording for `ScannerState::scan_cache`
+    segments: Option<Vec<ScanSegment>>,
 }
 
 impl<'input, 'provider, 'state, IncludeContext> Scanner<'input, 'provider, 'state, IncludeContext>
@@ -52,25 +285,29 @@ where
     pub fn new(
         input: &'input str,
         this_file: String,
-        prior_includes: &'state mut HashSet<String>,
-        skip_includes: &'state mut HashSet<String>,
+        state: &'state mut ScannerState,
         include_provider: &'provider mut dyn IncludeProvider<IncludeContext = IncludeContext>,
         include_context: IncludeContext,
     ) -> Scanner<'input, 'provider, 'state, IncludeContext> {
         Scanner {
             include_provider,
             include_context,
+            input,
             input_iter: LocationTracking {
                 iter: input.chars(),
-                line: 1,
+                location: Location::START,
             }
             .peekable(),
             this_file,
-            prior_includes,
-            skip_includes,
+            state,
+            conditionals: Vec::new(),
+            unevaluated_conditionals: 0,
+            include_guard: IncludeGuardDetection::Start,
             chunks: Vec::new(),
             current_chunk: String::new(),
-            current_chunk_first_line: 1,
+            current_chunk_start: Location::START,
+            pending_text: String::new(),
+            segments: None,
         }
     }
 
@@ -78,21 +315,172 @@ where
         self.chunks
     }
 
-    fn read_char(&mut self) -> Option<(u32, char)> {
+    fn read_char(&mut self) -> Option<(Location, char)> {
         self.input_iter.next()
     }
 
-    fn peek_char(&mut self) -> Option<&(u32, char)> {
+    fn peek_char(&mut self) -> Option<&(Location, char)> {
         self.input_iter.peek()
     }
 
+    /// Location of the next character, or the end of the input if there are none left.
+    fn location(&mut self) -> Location {
+        if let Some(&(location, _)) = self.peek_char() {
+            location
+        } else {
+            let mut location = Location::START;
+            self.input.chars().for_each(|c| location.advance(c));
+            location
+        }
+    }
+
+    fn dialect(&self) -> Dialect {
+        self.state.options.dialect
+    }
+
+    fn is_active(&self) -> bool {
+        self.conditionals.last().is_none_or(|group| group.active)
+    }
+
+    fn expands_macros(&self) -> bool {
+        cfg!(feature = "macros") && self.state.options.expand_macros
+    }
+
+    fn evaluates_conditionals(&self) -> bool {
+        self.state.options.evaluate_conditionals || self.expands_macros()
+    }
+
+    fn strips_inactive_code(&self) -> bool {
+        self.state.options.strip_inactive_code || self.expands_macros()
+    }
+
+    /// Outputs a character of regular code.
+    fn emit(&mut self, c: char) {
+        if let Some(segments) = &mut self.segments {
+            match segments.last_mut() {
+                Some(ScanSegment::Text(text)) => text.push(c),
+                _ => segments.push(ScanSegment::Text(c.to_string())),
+            }
+        }
+
+        if !c.is_whitespace() {
+            self.include_guard.on_token();
+        }
+
+        if c == '\n' || self.is_active() || !self.strips_inactive_code() {
+            if self.expands_macros() {
+                self.pending_text.push(c);
+            } else {
+                self.current_chunk.push(c);
+            }
+        }
+    }
+
+    fn emit_str(&mut self, s: &str) {
+        for c in s.chars() {
+            self.emit(c);
+        }
+    }
+
+    /// Outputs a directive previously consumed via `read_directive_line`.
+    /// Hidden directives only contribute their newlines, so that line numbers stay intact.
+    fn emit_directive(&mut self, output: &str, hidden: bool) {
+        if hidden {
+            self.current_chunk
+                .extend(output.chars().filter(|&c| c == '\n'));
+        } else {
+            self.current_chunk.push('#');
+            self.current_chunk.push_str(output);
+        }
+    }
+
+    /// Outputs a directive which the scanner doesn't interpret.
+    ///
+    /// The directive is copied verbatim rather than as regular code, so that e.g. `#version`
+    /// isn't subject to macro expansion, and doesn't count as code for include guard detection.
+    fn emit_unrecognized_directive(&mut self) {
+        let (output, _) = self.read_directive_line();
+        self.emit_directive(&output, !self.is_active() && self.strips_inactive_code());
+        self.track_unevaluated_directive(&output);
+
+        if let Some(segments) = &mut self.segments {
+            segments.push(ScanSegment::Directive(output));
+        }
+    }
+
+    /// Without conditional evaluation, conditionals and `#undef` are copied verbatim,
+    /// but still decide whether include guards can be relied upon.
+    fn track_unevaluated_directive(&mut self, output: &str) {
+        let (directive, args) = match split_ident(output.trim_start()) {
+            Some(directive) => directive,
+            None => return,
+        };
+
+        match directive {
+            "if" | "ifdef" | "ifndef" => self.unevaluated_conditionals += 1,
+            "endif" => {
+                self.unevaluated_conditionals = self.unevaluated_conditionals.saturating_sub(1);
+            }
+            "undef" => {
+                if let Some((name, _)) = split_ident(args) {
+                    for (file, guard) in &self.state.include_guards {
+                        if guard == name {
+                            self.state.unreliable_include_guards.insert(file.clone());
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Appends `pending_text` to the current chunk, expanding any macros in it.
+    fn expand_pending_text(&mut self) -> Result<(), PrepperError> {
+        #[cfg(feature = "macros")]
+        if !self.pending_text.is_empty() {
+            let first_line =
+                self.current_chunk_start.line as usize + self.current_chunk.matches('\n').count();
+
+            let expanded = crate::macros::expand_text(&self.pending_text, &self.state.defines)
+                .map_err(|(line, message)| PrepperError::MacroError {
+                    file: self.this_file.clone(),
+                    line: first_line + line,
+                    message,
+                    include_stack: self.include_stack_at(first_line + line),
+                })?;
+
+            self.current_chunk.push_str(&expanded);
+            self.pending_text.clear();
+        }
+
+        Ok(())
+    }
+
+    fn directive_error(&self, line: u32, message: impl Into<String>) -> PrepperError {
+        PrepperError::DirectiveError {
+            file: self.this_file.clone(),
+            line: line as usize,
+            message: message.into(),
+            include_stack: self.include_stack_at(line as usize),
+        }
+    }
+
+    /// The include stack for an error on `line` of the file being scanned.
+    fn include_stack_at(&self, line: usize) -> Vec<IncludeStackEntry> {
+        let mut include_stack = self.state.include_stack.clone();
+        if let Some(entry) = include_stack.last_mut() {
+            entry.line = line;
+        }
+        include_stack
+    }
+
     fn skip_whitespace_until_eol(&mut self) {
         while let Some(&(_, c)) = self.peek_char() {
             if c == '\n' {
                 break;
             } else if c.is_whitespace() {
                 let _ = self.read_char();
-            } else if c == '\\' {
+            } else if c == '\\' && has_line_continuations(self.dialect()) {
                 let mut peek_next = self.input_iter.clone();
                 let _ = peek_next.next();
                 if let Some(&(_, '\n')) = peek_next.peek() {
@@ -110,7 +498,7 @@ where
                     let _ = self.read_char();
                     let _ = self.read_char();
 
-                    self.input_iter = skip_block_comment(self.input_iter.clone()).1;
+                    self.input_iter = skip_block_comment(self.input_iter.clone(), self.dialect()).1;
                 }
             } else {
                 break;
@@ -124,9 +512,16 @@ where
         while let Some(&(_, c)) = self.peek_char() {
             if c == '\n' {
                 break;
-            } else if c == '\\' {
-                let _ = self.read_char();
+            } else if c == '\\' && self.dialect() != Dialect::Wgsl {
                 let _ = self.read_char();
+
+                if matches!(self.peek_char(), Some(&(_, '\n'))) {
+                    let _ = self.read_char();
+                } else if self.dialect() == Dialect::Hlsl {
+                    s.push(c);
+                } else {
+                    let _ = self.read_char();
+                }
             } else if c == right_delim {
                 let _ = self.read_char();
                 return Some(s);
@@ -142,59 +537,185 @@ where
     fn skip_line(&mut self) {
         while let Some((_, c)) = self.read_char() {
             if c == '\n' {
-                self.current_chunk.push('\n');
+                self.emit('\n');
                 break;
-            } else if c == '\\' {
+            } else if c == '\\' && has_line_continuations(self.dialect()) {
                 if let Some((_, '\n')) = self.read_char() {
-                    self.current_chunk.push('\n');
+                    self.emit('\n');
                 }
             }
         }
     }
 
+    /// Like `read_directive_line`, but doesn't consume any input, and only returns the logical line.
+    fn peek_directive_line(&mut self) -> String {
+        let input_iter = self.input_iter.clone();
+        let (_, logical) = self.read_directive_line();
+        self.input_iter = input_iter;
+        logical
+    }
+
+    /// Consumes the rest of a directive, up to but excluding the terminating newline.
+    ///
+    /// Returns the text to be copied into the output (with comments blanked out the same way
+    /// `process_input` does), and the logical line used for interpreting the directive
+    /// (with line continuations removed, and comments replaced by a single space).
+    fn read_directive_line(&mut self) -> (String, String) {
+        let mut output = String::new();
+        let mut logical = String::new();
+
+        while let Some(&(_, c)) = self.peek_char() {
+            if c == '\n' {
+                break;
+            }
+
+            let _ = self.read_char();
+
+            match c {
+                '\\' if has_line_continuations(self.dialect())
+                    && matches!(self.peek_char(), Some(&(_, '\n'))) =>
+                {
+                    let _ = self.read_char();
+                    output.push_str("\\\n");
+                }
+                '/' if matches!(self.peek_char(), Some(&(_, '*'))) => {
+                    let _ = self.read_char();
+                    let (white, it) = skip_block_comment(self.input_iter.clone(), self.dialect());
+                    self.input_iter = it;
+                    output.push_str("  ");
+                    output.push_str(&white);
+                    logical.push(' ');
+                }
+                '/' if matches!(self.peek_char(), Some(&(_, '/'))) => {
+                    // Line comment; like `skip_line`, but the newline is left for the caller.
+                    while let Some(&(_, c)) = self.peek_char() {
+                        if c == '\n' {
+                            break;
+                        }
+
+                        let _ = self.read_char();
+                        if c == '\\'
+                            && has_line_continuations(self.dialect())
+                            && matches!(self.peek_char(), Some(&(_, '\n')))
+                        {
+                            let _ = self.read_char();
+                            output.push('\n');
+                        }
+                    }
+                }
+                _ => {
+                    output.push(c);
+                    logical.push(c);
+                }
+            }
+        }
+
+        (output, logical)
+    }
+
     fn peek_preprocessor_ident(
         &mut self,
     ) -> Option<(String, Peekable<LocationTracking<Chars<'input>>>)> {
-        peek_preprocessor_ident(self.input_iter.clone())
+        peek_preprocessor_ident(self.input_iter.clone(), self.dialect())
     }
 
     fn flush_current_chunk(&mut self) {
         if !self.current_chunk.is_empty() {
             self.chunks.push(SourceChunk {
                 file: self.this_file.clone(),
-                line_offset: (self.current_chunk_first_line - 1) as usize,
+                line_offset: (self.current_chunk_start.line - 1) as usize,
+                column_offset: (self.current_chunk_start.column - 1) as usize,
                 source: self.current_chunk.clone(),
                 context: self.include_context.clone(),
             });
             self.current_chunk.clear();
         }
 
-        if let Some(&(line, _)) = self.peek_char() {
-            self.current_chunk_first_line = line;
+        if let Some(&(location, _)) = self.peek_char() {
+            self.current_chunk_start = location;
         }
     }
 
-    pub fn include_child(&mut self, path: &str, included_on_line: u32) -> Result<(), PrepperError> {
-        if self.prior_includes.contains(path) {
-            return Err(PrepperError::RecursiveInclude {
-                file: path.to_string(),
-                from: self.this_file.clone(),
-                from_line: included_on_line as usize,
-            });
-        }
-
+    pub fn include_child(
+        &mut self,
+        path: &str,
+        kind: IncludeKind,
+        included_on_line: u32,
+    ) -> Result<ResolvedIncludePath, PrepperError> {
         self.flush_current_chunk();
 
+        // The placeholder scanner which includes the root file isn't on the stack.
+        let includer = if self.state.include_stack.is_empty() {
+            None
+        } else {
+            Some(ResolvedIncludePath(self.this_file.clone()))
+        };
+
         let child = self
             .include_provider
-            .resolve_path(path, &self.include_context)
+            .resolve_path(path, kind, includer.as_ref(), &self.include_context)
             .map_err(|e| PrepperError::IncludeProviderError {
                 file: path.to_string(),
                 cause: e,
+                include_stack: self.include_stack_at(included_on_line as usize),
             })?;
 
-        if self.skip_includes.contains(&child.resolved_path.0) {
-            return Ok(());
+        let child_idx = self.state.include_graph.add_file(&child.resolved_path);
+        if let Some(includer) = &includer {
+            let includer_idx = self.state.include_graph.add_file(includer);
+            self.state.include_graph.edges.push(IncludeEdge {
+                from: includer_idx,
+                to: child_idx,
+                line: included_on_line as usize,
+            });
+        }
+
+        if self.state.skip_includes.contains(&child.resolved_path.0) {
+            return Ok(child.resolved_path);
+        }
+
+        if let Some(guard) = self.state.include_guards.get(&child.resolved_path.0) {
+            let guard_set = if self.evaluates_conditionals() {
+                self.state.defines.contains_key(guard)
+            } else {
+                // Defines aren't tracked; assume the guard is still set unless the file might
+                // have been skipped by the compiler, or the guard undone. Within the file itself,
+                // past the guard's `#define`, it is set regardless.
+                !self
+                    .state
+                    .unreliable_include_guards
+                    .contains(&child.resolved_path.0)
+                    || self
+                        .state
+                        .include_stack
+                        .iter()
+                        .any(|entry| entry.file == child.resolved_path.0)
+            };
+
+            if guard_set {
+                return Ok(child.resolved_path);
+            }
+        }
+
+        if let Some(cycle_start) = self
+            .state
+            .include_stack
+            .iter()
+            .position(|entry| entry.file == child.resolved_path.0)
+        {
+            let mut cycle: Vec<String> = self.state.include_stack[cycle_start..]
+                .iter()
+                .map(|entry| entry.file.clone())
+                .collect();
+            cycle.push(child.resolved_path.0.clone());
+
+            return Err(PrepperError::RecursiveInclude {
+                file: child.resolved_path.0,
+                from: self.this_file.clone(),
+                from_line: included_on_line as usize,
+                cycle,
+                include_stack: self.include_stack_at(included_on_line as usize),
+            });
         }
 
         let child_code = self
@@ -203,110 +724,546 @@ where
             .map_err(|e| PrepperError::IncludeProviderError {
                 file: path.to_string(),
                 cause: e,
+                include_stack: self.include_stack_at(included_on_line as usize),
             })?;
 
-        self.prior_includes.insert(path.to_string());
+        if let Some(entry) = self.state.include_stack.last_mut() {
+            entry.line = included_on_line as usize;
+        }
+        self.state.include_stack.push(IncludeStackEntry {
+            file: child.resolved_path.0.clone(),
+            line: 0,
+        });
+
+        let cache_key = self.state.scan_cache.as_ref().map(|_| {
+            (
+                child.resolved_path.0.clone(),
+                xxhash_rust::xxh3::xxh3_64(child_code.as_bytes()),
+            )
+        });
+        let cached_scan = cache_key
+            .as_ref()
+            .and_then(|key| self.state.scan_cache.as_ref()?.get(key).cloned());
+
+        // The file's own include guard is open around all of its includes, but doesn't count:
+        // the file is only scanned when its guard isn't set.
+        let enclosing_conditionals = self.state.enclosing_conditionals;
+        let own_guard = matches!(self.include_guard, IncludeGuardDetection::Open { .. }) as u32;
+        let child_enclosing_conditionals =
+            enclosing_conditionals + self.unevaluated_conditionals.saturating_sub(own_guard);
+        self.state.enclosing_conditionals = child_enclosing_conditionals;
 
         self.chunks.append(&mut {
             let mut child_scanner = Scanner::new(
                 &child_code,
                 child.resolved_path.0.to_string(),
-                self.prior_includes,
-                self.skip_includes,
+                self.state,
                 self.include_provider,
                 child.context,
             );
-            child_scanner.process_input()?;
+
+            if let Some(cached_scan) = cached_scan {
+                child_scanner.replay(&cached_scan)?;
+            } else {
+                // Scans depending on macros aren't reusable.
+                if cache_key.is_some() && !child_scanner.expands_macros() {
+                    child_scanner.segments = Some(Vec::new());
+                }
+
+                child_scanner.process_input()?;
+
+                if let (Some(key), Some(segments)) = (cache_key, child_scanner.segments.take()) {
+                    let include_guard = match &child_scanner.include_guard {
+                        IncludeGuardDetection::Closed { name } => Some(name.clone()),
+                        _ => None,
+                    };
+
+                    if let Some(scan_cache) = &mut child_scanner.state.scan_cache {
+                        scan_cache.insert(
+                            key,
+                            Arc::new(CachedScan {
+                                segments,
+                                include_guard,
+                            }),
+                        );
+                    }
+                }
+            }
+
             child_scanner.chunks
         });
 
-        self.prior_includes.remove(path);
+        self.state.enclosing_conditionals = enclosing_conditionals;
+        if child_enclosing_conditionals > 0 {
+            self.state
+                .unreliable_include_guards
+                .insert(child.resolved_path.0.clone());
+        } else {
+            self.state
+                .unreliable_include_guards
+                .remove(&child.resolved_path.0);
+        }
+
+        self.state.include_stack.pop();
+
+        Ok(child.resolved_path)
+    }
+
+    /// Reproduces the effects of `process_input` from an earlier scan of the same file.
+    fn replay(&mut self, scan: &CachedScan) -> Result<(), PrepperError> {
+        // The guard's group encloses everything else in the file.
+        if let Some(name) = &scan.include_guard {
+            self.include_guard = IncludeGuardDetection::Open {
+                name: name.clone(),
+                defined: true,
+                depth: 1,
+            };
+        }
+
+        for segment in &scan.segments {
+            match segment {
+                ScanSegment::Text(text) => self.emit_str(text),
+                ScanSegment::Directive(output) => {
+                    self.emit_directive(output, !self.is_active() && self.strips_inactive_code());
+                    self.track_unevaluated_directive(output);
+                }
+                ScanSegment::Conditional {
+                    line,
+                    output,
+                    logical,
+                } => self.apply_conditional(*line, output, logical)?,
+                ScanSegment::Define {
+                    line,
+                    output,
+                    logical,
+                } => self.apply_define(*line, output, logical)?,
+                ScanSegment::Include {
+                    path,
+                    kind,
+                    line,
+                    resume,
+                } => {
+                    if self.is_active() {
+                        self.include_child(path, *kind, *line)?;
+                        self.current_chunk_start = *resume;
+                    }
+                }
+                ScanSegment::PragmaOnce => {
+                    self.state.skip_includes.insert(self.this_file.clone());
+                }
+                ScanSegment::IncludeGuard(name) => {
+                    self.state
+                        .include_guards
+                        .insert(self.this_file.clone(), name.clone());
+                }
+            }
+        }
+
+        if let Some(name) = &scan.include_guard {
+            self.state
+                .include_guards
+                .insert(self.this_file.clone(), name.clone());
+        } else {
+            self.state.include_guards.remove(&self.this_file);
+        }
 
+        self.flush_current_chunk();
         Ok(())
     }
 
     pub fn process_input(&mut self) -> Result<(), PrepperError> {
-        while let Some((c_line, c)) = self.read_char() {
+        while let Some((Location { line: c_line, .. }, c)) = self.read_char() {
             match c {
                 '/' => {
                     let next = self.peek_char();
 
                     if let Some(&(_, '*')) = next {
                         let _ = self.read_char();
-                        self.current_chunk.push_str("  ");
-                        let (white, it) = skip_block_comment(self.input_iter.clone());
+                        self.emit_str("  ");
+                        let (white, it) =
+                            skip_block_comment(self.input_iter.clone(), self.dialect());
 
                         self.input_iter = it;
-                        self.current_chunk.push_str(&white);
+                        self.emit_str(&white);
                     } else if let Some(&(_, '/')) = next {
                         let _ = self.read_char();
                         self.skip_line();
                     } else {
-                        self.current_chunk.push(c);
+                        self.emit(c);
                     }
                 }
                 '#' => {
+                    self.expand_pending_text()?;
+
                     if let Some(preprocessor_ident) = self.peek_preprocessor_ident() {
-                        if "include" == preprocessor_ident.0 {
-                            self.input_iter = preprocessor_ident.1;
-                            self.skip_whitespace_until_eol();
-
-                            let left_delim = self.read_char();
-
-                            let right_delim = match left_delim {
-                                Some((_, '"')) => Some('"'),
-                                Some((_, '<')) => Some('>'),
-                                _ => None,
-                            };
-
-                            let path = right_delim
-                                .map(|right_delim| self.read_string(right_delim))
-                                .unwrap_or_default();
-
-                            if let Some(ref path) = path {
-                                self.include_child(path, c_line)?;
-                            } else {
-                                return Err(PrepperError::ParseError {
-                                    file: self.this_file.clone(),
-                                    line: c_line as usize,
-                                });
+                        let evaluate_conditionals = self.evaluates_conditionals();
+                        let module_imports = self.state.options.module_imports;
+
+                        if preprocessor_ident.0.is_empty() {
+                            self.include_guard.on_token();
+                        } else {
+                            let logical = self.peek_directive_line();
+                            let args = split_ident(&logical).map_or("", |(_, args)| args);
+                            self.include_guard.on_directive(&preprocessor_ident.0, args);
+
+                            // Register the guard right away, so that the file including itself,
+                            // directly or not, sees it as guarded rather than recursive.
+                            if let IncludeGuardDetection::Open {
+                                name,
+                                defined: true,
+                                ..
+                            } = &self.include_guard
+                            {
+                                if self.state.include_guards.get(&self.this_file) != Some(name) {
+                                    self.state
+                                        .include_guards
+                                        .insert(self.this_file.clone(), name.clone());
+
+                                    if let Some(segments) = &mut self.segments {
+                                        segments.push(ScanSegment::IncludeGuard(name.clone()));
+                                    }
+                                }
                             }
-                        } else if "pragma" == preprocessor_ident.0 {
-                            let next_ident = peek_preprocessor_ident(preprocessor_ident.1);
+                        }
 
-                            match next_ident {
-                                Some((pragma_type, next_iter)) if pragma_type == "once" => {
-                                    self.input_iter = next_iter;
-                                    self.skip_whitespace_until_eol();
+                        // Whether `#pragma once` takes effect would depend on the conditionals.
+                        if preprocessor_ident.0 == "pragma" && !self.conditionals.is_empty() {
+                            self.segments = None;
+                        }
+
+                        let directive = match preprocessor_ident.0.as_str() {
+                            "import"
+                                if self.dialect() == Dialect::Wgsl
+                                    && modules::is_file_import(&self.peek_directive_line()) =>
+                            {
+                                "include"
+                            }
+                            directive => directive,
+                        };
 
-                                    self.skip_includes.insert(self.this_file.clone());
+                        match directive {
+                            "import" | "define_import_path"
+                                if module_imports && !self.is_active() =>
+                            {
+                                // Never reached by the compiler; don't crawl it.
+                                self.segments = None;
+                                let _ = self.read_directive_line();
+                            }
+                            "import" if module_imports => {
+                                self.import_module(c_line)?;
+                            }
+                            "define_import_path" if module_imports => {
+                                // Modules are named by their importers; nothing to check.
+                                let _ = self.read_directive_line();
+                            }
+                            "include" => {
+                                self.input_iter = preprocessor_ident.1;
+                                self.skip_whitespace_until_eol();
+
+                                let left_delim_location = self.location();
+                                let left_delim = self.read_char();
+
+                                let (kind, right_delim) = match left_delim {
+                                    Some((_, '"')) => (IncludeKind::Quoted, Some('"')),
+                                    Some((_, '<')) => (IncludeKind::AngleBracket, Some('>')),
+                                    _ => (IncludeKind::Quoted, None),
+                                };
+
+                                // Point at the missing closing delimiter, or at the bad opening one.
+                                let path = right_delim
+                                    .map(|right_delim| {
+                                        self.read_string(right_delim).ok_or_else(|| self.location())
+                                    })
+                                    .unwrap_or(Err(left_delim_location));
+
+                                match path {
+                                    Ok(path) => {
+                                        let resume = self.location();
+                                        if let Some(segments) = &mut self.segments {
+                                            segments.push(ScanSegment::Include {
+                                                path: path.clone(),
+                                                kind,
+                                                line: c_line,
+                                                resume,
+                                            });
+                                        }
+
+                                        // Inactive includes are never reached by the compiler;
+                                        // don't crawl them.
+                                        if self.is_active() {
+                                            self.include_child(&path, kind, c_line)?;
+                                        }
+                                    }
+                                    Err(_) if !self.is_active() => {
+                                        // Never reached by the compiler, so not an error
+                                        self.segments = None;
+                                        let _ = self.read_directive_line();
+                                    }
+                                    Err(location) => {
+                                        return Err(PrepperError::ParseError {
+                                            file: self.this_file.clone(),
+                                            line: location.line as usize,
+                                            column: location.column as usize,
+                                            byte_offset: location.byte_offset,
+                                            include_stack: self
+                                                .include_stack_at(location.line as usize),
+                                        });
+                                    }
                                 }
-                                _ => {
-                                    self.current_chunk.push(c);
+                            }
+                            "pragma" if self.is_active() => {
+                                let next_ident =
+                                    peek_preprocessor_ident(preprocessor_ident.1, self.dialect());
+
+                                match next_ident {
+                                    Some((pragma_type, next_iter)) if pragma_type == "once" => {
+                                        self.input_iter = next_iter;
+                                        self.skip_whitespace_until_eol();
+
+                                        self.state.skip_includes.insert(self.this_file.clone());
+
+                                        if let Some(segments) = &mut self.segments {
+                                            segments.push(ScanSegment::PragmaOnce);
+                                        }
+                                    }
+                                    _ => {
+                                        self.emit_unrecognized_directive();
+                                    }
                                 }
                             }
-                        } else {
-                            self.current_chunk.push(c);
+                            "if" | "ifdef" | "ifndef" | "elif" | "else" | "endif"
+                                if evaluate_conditionals =>
+                            {
+                                self.process_conditional(c_line)?;
+                            }
+                            "define" | "undef" if evaluate_conditionals => {
+                                self.process_define(c_line)?;
+                            }
+                            _ => {
+                                self.emit_unrecognized_directive();
+                            }
                         }
                     } else {
-                        self.current_chunk.push(c);
+                        self.emit(c);
                     }
                 }
                 _ => {
-                    self.current_chunk.push(c);
+                    self.emit(c);
                 }
             }
         }
 
+        if let Some(group) = self.conditionals.last() {
+            return Err(self.directive_error(group.line, "unterminated conditional directive"));
+        }
+
+        self.expand_pending_text()?;
+
+        if let IncludeGuardDetection::Closed { name } = &self.include_guard {
+            self.state
+                .include_guards
+                .insert(self.this_file.clone(), name.clone());
+        } else {
+            // Registered early, but the file turned out not to be guarded after all
+            self.state.include_guards.remove(&self.this_file);
+        }
+
         self.flush_current_chunk();
         Ok(())
     }
+
+    /// Handles an `#import` of a module, whose `#` has just been read.
+    fn import_module(&mut self, line: u32) -> Result<(), PrepperError> {
+        // The imports are recorded in the state, which replaying a scan wouldn't do.
+        self.segments = None;
+
+        let (_, logical) = self.read_directive_line();
+        let args = split_ident(&logical).map_or("", |(_, args)| args);
+        let import = modules::parse_import(args)
+            .ok_or_else(|| self.directive_error(line, "malformed #import"))?;
+
+        let path = modules::module_file_path(&import.module, self.dialect());
+        let file = self.include_child(&path, IncludeKind::AngleBracket, line)?;
+
+        // Each module is included once per root.
+        self.state.skip_includes.insert(file.0.clone());
+        self.state.imports.push(ImportedModule {
+            importer: self.this_file.clone(),
+            import,
+            file: file.0,
+        });
+
+        Ok(())
+    }
+
+    /// Handles a conditional directive, whose `#` has just been read.
+    fn process_conditional(&mut self, line: u32) -> Result<(), PrepperError> {
+        let (output, logical) = self.read_directive_line();
+        self.apply_conditional(line, &output, &logical)?;
+
+        if let Some(segments) = &mut self.segments {
+            segments.push(ScanSegment::Conditional {
+                line,
+                output,
+                logical,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Evaluates a conditional directive read by `process_conditional`.
+    fn apply_conditional(
+        &mut self,
+        line: u32,
+        output: &str,
+        logical: &str,
+    ) -> Result<(), PrepperError> {
+        let (directive, args) = split_ident(logical).unwrap_or_default();
+
+        let is_active = self.is_active();
+        let parent_active = self
+            .conditionals
+            .last()
+            .is_none_or(|group| group.parent_active);
+
+        match directive {
+            "if" | "ifdef" | "ifndef" => {
+                let condition = if !is_active {
+                    // Nested in an inactive group; its condition doesn't matter.
+                    false
+                } else if directive == "if" {
+                    expression::evaluate(args, &self.state.defines)
+                        .map_err(|message| self.directive_error(line, message))?
+                        != 0
+                } else {
+                    let name = match split_ident(args) {
+                        Some((name, rest)) if rest.trim().is_empty() => name,
+                        _ => {
+                            return Err(self.directive_error(
+                                line,
+                                format!("expected a macro name after #{}", directive),
+                            ))
+                        }
+                    };
+
+                    self.state.defines.contains_key(name) == (directive == "ifdef")
+                };
+
+                self.emit_directive(output, self.hides_directive(is_active));
+
+                self.conditionals.push(ConditionalGroup {
+                    line,
+                    parent_active: is_active,
+                    branch_taken: condition,
+                    active: condition,
+                    seen_else: false,
+                });
+            }
+            "elif" | "else" => {
+                let group = match self.conditionals.last() {
+                    Some(group) if !group.seen_else => group,
+                    Some(_) => {
+                        return Err(
+                            self.directive_error(line, format!("#{} after #else", directive))
+                        )
+                    }
+                    None => {
+                        return Err(
+                            self.directive_error(line, format!("#{} without #if", directive))
+                        )
+                    }
+                };
+
+                let condition = if !group.parent_active || group.branch_taken {
+                    false
+                } else if directive == "elif" {
+                    expression::evaluate(args, &self.state.defines)
+                        .map_err(|message| self.directive_error(line, message))?
+                        != 0
+                } else {
+                    true
+                };
+
+                self.emit_directive(output, self.hides_directive(parent_active));
+
+                let group = self.conditionals.last_mut().unwrap();
+                group.active = condition;
+                group.branch_taken |= condition;
+                group.seen_else = directive == "else";
+            }
+            _ => {
+                if self.conditionals.pop().is_none() {
+                    return Err(self.directive_error(line, "#endif without #if"));
+                }
+
+                self.emit_directive(output, self.hides_directive(parent_active));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether a conditional or macro definition directive should be left out of the output.
+    /// Once macros are expanded by the scanner, the compiler doesn't need them anymore.
+    fn hides_directive(&self, visible: bool) -> bool {
+        self.expands_macros() || (!visible && self.strips_inactive_code())
+    }
+
+    /// Handles `#define` or `#undef`, whose `#` has just been read.
+    fn process_define(&mut self, line: u32) -> Result<(), PrepperError> {
+        let (output, logical) = self.read_directive_line();
+        self.apply_define(line, &output, &logical)?;
+
+        if let Some(segments) = &mut self.segments {
+            segments.push(ScanSegment::Define {
+                line,
+                output,
+                logical,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Applies a `#define` or `#undef` read by `process_define`.
+    fn apply_define(&mut self, line: u32, output: &str, logical: &str) -> Result<(), PrepperError> {
+        self.emit_directive(output, self.hides_directive(self.is_active()));
+
+        if !self.is_active() {
+            return Ok(());
+        }
+
+        let (directive, args) = split_ident(logical).unwrap_or_default();
+
+        if directive == "define" {
+            let (name, definition) = parse_define(args)
+                .ok_or_else(|| self.directive_error(line, "malformed #define"))?;
+            self.state.defines.insert(name, definition);
+        } else {
+            let name = split_ident(args)
+                .map(|(name, _)| name)
+                .ok_or_else(|| self.directive_error(line, "expected a macro name after #undef"))?;
+            self.state.defines.remove(name);
+        }
+
+        Ok(())
+    }
 }
 
+/// Whether a backslash followed by a newline joins lines. WGSL has no such thing.
+fn has_line_continuations(dialect: Dialect) -> bool {
+    dialect != Dialect::Wgsl
+}
+
+/// Skips the rest of a block comment whose `/*` has just been read, returning whitespace
+/// spanning the same lines. Block comments nest in WGSL.
 fn skip_block_comment(
     mut it: Peekable<LocationTracking<Chars<'_>>>,
+    dialect: Dialect,
 ) -> (String, Peekable<LocationTracking<Chars<'_>>>) {
     let mut s = String::new();
+    let mut depth = 1;
 
     while let Some((_, c)) = it.next() {
         if c == '*' {
@@ -314,8 +1271,16 @@ fn skip_block_comment(
             if let Some(&(_, '/')) = it.peek() {
                 let _ = it.next();
                 s.push(' ');
-                break;
+
+                depth -= 1;
+                if depth == 0 {
+                    break;
+                }
             }
+        } else if c == '/' && dialect == Dialect::Wgsl && matches!(it.peek(), Some(&(_, '*'))) {
+            let _ = it.next();
+            s.push_str("  ");
+            depth += 1;
         } else if c == '\n' {
             s.push('\n');
         } else {
@@ -328,13 +1293,14 @@ fn skip_block_comment(
 
 fn peek_preprocessor_ident(
     mut it: Peekable<LocationTracking<Chars<'_>>>,
+    dialect: Dialect,
 ) -> Option<(String, Peekable<LocationTracking<Chars<'_>>>)> {
     let mut token = String::new();
 
     while let Some(&(_, c)) = it.peek() {
         if '\n' == c || '\r' == c {
             break;
-        } else if c.is_alphabetic() {
+        } else if c.is_alphabetic() || c == '_' {
             let _ = it.next();
             token.push(c);
         } else if c.is_whitespace() {
@@ -345,7 +1311,7 @@ fn peek_preprocessor_ident(
                 // Still haven't found anything. Continue scanning.
                 let _ = it.next();
             }
-        } else if '\\' == c {
+        } else if '\\' == c && has_line_continuations(dialect) {
             let _ = it.next();
             let next = it.next();
 
@@ -374,7 +1340,7 @@ fn peek_preprocessor_ident(
                 let _ = it.next();
                 let _ = it.next();
 
-                it = skip_block_comment(it).1;
+                it = skip_block_comment(it, dialect).1;
             } else {
                 // Something other than a block comment. End the identifier.
                 break;
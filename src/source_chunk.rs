@@ -13,4 +13,8 @@ pub struct SourceChunk<IncludeContext> {
 
     /// Line in the `file` at which this snippet starts
     pub line_offset: usize,
+
+    /// Column in the first line of the snippet at which it starts, counted in characters.
+    /// Non-zero when the snippet follows an `#include` on the same line.
+    pub column_offset: usize,
 }
@@ -0,0 +1,194 @@
+use crate::{json, SourceChunk};
+
+/// Location in one of the original files. Lines and columns are zero-based,
+/// and columns count characters.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceLocation {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+/// Maps locations between the concatenated sources of a list of [`SourceChunk`]s
+/// and the original files they came from.
+///
+/// Lines and columns are zero-based in both directions, and columns count characters.
+pub struct SourceMap {
+    sources: Vec<String>,
+
+    // Sorted by generated location
+    segments: Vec<Segment>,
+}
+
+/// Part of a single line of generated code coming from a single chunk
+#[derive(Clone, Copy, Debug)]
+struct Segment {
+    generated_line: usize,
+    generated_column: usize,
+    length: usize,
+    source: usize,
+    original_line: usize,
+    original_column: usize,
+}
+
+impl SourceMap {
+    /// Build a source map for the concatenation of all `chunks`' sources, in order.
+    pub fn from_chunks<IncludeContext>(chunks: &[SourceChunk<IncludeContext>]) -> Self {
+        let mut sources: Vec<String> = Vec::new();
+        let mut segments = Vec::new();
+        let mut generated_line = 0;
+        let mut generated_column = 0;
+
+        for chunk in chunks {
+            let source = match sources.iter().position(|s| *s == chunk.file) {
+                Some(source) => source,
+                None => {
+                    sources.push(chunk.file.clone());
+                    sources.len() - 1
+                }
+            };
+
+            let lines: Vec<&str> = chunk.source.split('\n').collect();
+
+            for (i, line) in lines.iter().enumerate() {
+                let length = line.chars().count();
+
+                // Nothing follows the chunk's final newline.
+                if i + 1 < lines.len() || length > 0 {
+                    segments.push(Segment {
+                        generated_line,
+                        generated_column,
+                        length,
+                        source,
+                        original_line: chunk.line_offset + i,
+                        original_column: if i == 0 { chunk.column_offset } else { 0 },
+                    });
+                }
+
+                if i + 1 < lines.len() {
+                    generated_line += 1;
+                    generated_column = 0;
+                } else {
+                    generated_column += length;
+                }
+            }
+        }
+
+        Self { sources, segments }
+    }
+
+    /// Files referenced by the map, in order of first appearance.
+    pub fn sources(&self) -> &[String] {
+        &self.sources
+    }
+
+    /// Find the original location of a `line` and `column` in the generated code.
+    pub fn original_location(&self, line: usize, column: usize) -> Option<SourceLocation> {
+        let idx = self
+            .segments
+            .partition_point(|s| (s.generated_line, s.generated_column) <= (line, column));
+        let segment = self.segments[..idx].last()?;
+
+        if segment.generated_line != line {
+            return None;
+        }
+
+        Some(SourceLocation {
+            file: self.sources[segment.source].clone(),
+            line: segment.original_line,
+            column: segment.original_column + (column - segment.generated_column),
+        })
+    }
+
+    /// Find the generated `(line, column)` corresponding to a location in an original file.
+    ///
+    /// If the file was included more than once, the first occurrence is returned.
+    pub fn generated_location(&self, location: &SourceLocation) -> Option<(usize, usize)> {
+        let source = self.sources.iter().position(|s| *s == location.file)?;
+
+        self.segments
+            .iter()
+            .filter(|s| {
+                s.source == source
+                    && s.original_line == location.line
+                    && s.original_column <= location.column
+            })
+            .find(|s| location.column <= s.original_column + s.length)
+            .map(|s| {
+                (
+                    s.generated_line,
+                    s.generated_column + (location.column - s.original_column),
+                )
+            })
+    }
+
+    /// Serialize as a [Source Map Revision 3](https://sourcemaps.info/spec.html) JSON object.
+    ///
+    /// `file` is the name of the generated file, if any.
+    pub fn to_json(&self, file: Option<&str>) -> String {
+        let mut mappings = String::new();
+        let mut line = 0;
+        let mut prev_column = 0;
+        let mut prev_source = 0;
+        let mut prev_original_line = 0;
+        let mut prev_original_column = 0;
+        let mut first_in_line = true;
+
+        for segment in &self.segments {
+            while line < segment.generated_line {
+                mappings.push(';');
+                line += 1;
+                prev_column = 0;
+                first_in_line = true;
+            }
+
+            if !first_in_line {
+                mappings.push(',');
+            }
+            first_in_line = false;
+
+            for (value, prev) in [
+                (segment.generated_column, &mut prev_column),
+                (segment.source, &mut prev_source),
+                (segment.original_line, &mut prev_original_line),
+                (segment.original_column, &mut prev_original_column),
+            ] {
+                encode_vlq(&mut mappings, value as i64 - *prev as i64);
+                *prev = value;
+            }
+        }
+
+        let sources: Vec<String> = self.sources.iter().map(|s| json::string(s)).collect();
+
+        format!(
+            "{{\"version\":3,{}\"sources\":[{}],\"names\":[],\"mappings\":{}}}",
+            file.map(|f| format!("\"file\":{},", json::string(f)))
+                .unwrap_or_default(),
+            sources.join(","),
+            json::string(&mappings)
+        )
+    }
+}
+
+fn encode_vlq(out: &mut String, value: i64) {
+    const BASE64: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut vlq = if value < 0 {
+        ((-value) << 1) | 1
+    } else {
+        value << 1
+    } as u64;
+
+    loop {
+        let mut digit = (vlq & 31) as usize;
+        vlq >>= 5;
+        if vlq > 0 {
+            digit |= 32;
+        }
+        out.push(BASE64[digit] as char);
+
+        if vlq == 0 {
+            break;
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
 
 use crate::ResolvedIncludePath;
 
@@ -9,6 +9,8 @@ impl crate::IncludeProvider for DummyIncludeProvider {
     fn resolve_path(
         &self,
         path: &str,
+        _kind: crate::IncludeKind,
+        _includer: Option<&ResolvedIncludePath>,
         _context: &Self::IncludeContext,
     ) -> Result<crate::ResolvedInclude<Self::IncludeContext>, crate::BoxedIncludeProviderError>
     {
@@ -33,6 +35,8 @@ impl crate::IncludeProvider for HashMapIncludeProvider {
     fn resolve_path(
         &self,
         path: &str,
+        _kind: crate::IncludeKind,
+        _includer: Option<&ResolvedIncludePath>,
         _context: &Self::IncludeContext,
     ) -> Result<crate::ResolvedInclude<Self::IncludeContext>, crate::BoxedIncludeProviderError>
     {
@@ -55,14 +59,26 @@ fn preprocess_into_string<IncludeContext: Clone>(
     include_provider: &mut dyn crate::IncludeProvider<IncludeContext = IncludeContext>,
     include_context: IncludeContext,
 ) -> Result<String, crate::PrepperError> {
-    let mut prior_includes = HashSet::new();
-    let mut skip_includes = HashSet::new();
+    preprocess_into_string_with_options(
+        s,
+        include_provider,
+        include_context,
+        &crate::ProcessOptions::default(),
+    )
+}
+
+fn preprocess_into_string_with_options<IncludeContext: Clone>(
+    s: &str,
+    include_provider: &mut dyn crate::IncludeProvider<IncludeContext = IncludeContext>,
+    include_context: IncludeContext,
+    options: &crate::ProcessOptions,
+) -> Result<String, crate::PrepperError> {
+    let mut state = crate::ScannerState::new(options.clone());
 
     let mut scanner = crate::Scanner::new(
         s,
         "no-file".to_string(),
-        &mut prior_includes,
-        &mut skip_includes,
+        &mut state,
         include_provider,
         include_context,
     );
@@ -137,7 +153,12 @@ fn basic_include() {
 #[test]
 fn multi_line_include() {
     match preprocess_into_string("#inc\\\nlude", &mut DummyIncludeProvider, ()) {
-        Err(crate::PrepperError::ParseError { file: _, line: 1 }) => (),
+        Err(crate::PrepperError::ParseError {
+            file: _,
+            line: 2,
+            column: 5,
+            ..
+        }) => (),
         _ => panic!(),
     }
 
@@ -177,30 +198,35 @@ fn multi_level_include() {
             crate::SourceChunk {
                 file: "foo".to_string(),
                 line_offset: 0,
+                column_offset: 0,
                 source: "double rainbow;\n".to_string(),
                 context: (),
             },
             crate::SourceChunk {
                 file: "bar".to_string(),
                 line_offset: 0,
+                column_offset: 0,
                 source: "int bar;".to_string(),
                 context: (),
             },
             crate::SourceChunk {
                 file: "foo".to_string(),
                 line_offset: 1,
+                column_offset: 14,
                 source: "\nint spam;\n".to_string(),
                 context: (),
             },
             crate::SourceChunk {
                 file: "baz".to_string(),
                 line_offset: 0,
+                column_offset: 0,
                 source: "int baz;".to_string(),
                 context: (),
             },
             crate::SourceChunk {
                 file: "foo".to_string(),
                 line_offset: 3,
+                column_offset: 14,
                 source: "\nvoid ham();".to_string(),
                 context: (),
             },
@@ -228,20 +254,158 @@ fn pragma_once() {
     );
 }
 
+#[test]
+fn include_guards() {
+    let mut include_provider = HashMapIncludeProvider(
+        [
+            (
+                "guarded",
+                "// Comment\n#ifndef FOO_H\n#define FOO_H\nthis_is_foo\n#endif\n",
+            ),
+            (
+                "guarded2",
+                "#if !defined(BAR_H)\n#define BAR_H\n#ifdef X\n#endif\nthis_is_bar\n#endif",
+            ),
+            (
+                "unguarded",
+                "#ifndef BAZ_H\n#define BAZ_H\n#endif\nthis_is_baz\n",
+            ),
+            (
+                "unguarded2",
+                "#ifndef QUX_H\n#define QUX_H\n#else\n#endif\nthis_is_qux\n",
+            ),
+        ]
+        .iter()
+        .map(|(a, b)| (a.to_string(), b.to_string()))
+        .collect(),
+    );
+
+    for (file, token, expected_count) in [
+        ("guarded", "this_is_foo", 1),
+        ("guarded2", "this_is_bar", 1),
+        ("unguarded", "this_is_baz", 3),
+        ("unguarded2", "this_is_qux", 3),
+    ]
+    .iter()
+    {
+        let source = format!("#include <{0}>\n#include <{0}>\n#include <{0}>", file);
+        let output = preprocess_into_string(&source, &mut include_provider, ()).unwrap();
+        assert_eq!(output.matches(token).count(), *expected_count, "{}", file);
+    }
+
+    // Without tracking defines, the guard is only honored if nothing could have kept it unset.
+    for (source, expected_count) in [
+        (
+            "#ifdef A\n#include <guarded>\n#endif\n#include <guarded>",
+            2,
+        ),
+        ("#include <guarded>\n#undef FOO_H\n#include <guarded>", 2),
+        (
+            "#ifdef A\n#endif\n#include <guarded>\n#include <guarded>",
+            1,
+        ),
+    ]
+    .iter()
+    {
+        let output = preprocess_into_string(source, &mut include_provider, ()).unwrap();
+        assert_eq!(
+            output.matches("this_is_foo").count(),
+            *expected_count,
+            "{}",
+            source
+        );
+    }
+
+    // When defines are tracked, the guard is only honored while its macro is defined.
+    let options = crate::ProcessOptions {
+        evaluate_conditionals: true,
+        strip_inactive_code: true,
+        ..Default::default()
+    };
+
+    let output = preprocess_into_string_with_options(
+        "#include <guarded>\n#include <guarded>\n#undef FOO_H\n#include <guarded>",
+        &mut include_provider,
+        (),
+        &options,
+    )
+    .unwrap();
+    assert_eq!(output.matches("this_is_foo").count(), 2);
+
+    // Mutually including headers stop at the guards, rather than being reported as recursive.
+    let mut include_provider = HashMapIncludeProvider(
+        [
+            (
+                "a",
+                "#ifndef A_H\n#define A_H\n#include <b>\nthis_is_a\n#endif\n",
+            ),
+            (
+                "b",
+                "#ifndef B_H\n#define B_H\n#include <a>\nthis_is_b\n#endif\n",
+            ),
+        ]
+        .iter()
+        .map(|(a, b)| (a.to_string(), b.to_string()))
+        .collect(),
+    );
+
+    for options in [crate::ProcessOptions::default(), options] {
+        let output = preprocess_into_string_with_options(
+            "#include <a>\n#include <b>",
+            &mut include_provider,
+            (),
+            &options,
+        )
+        .unwrap();
+        assert_eq!(output.matches("this_is_a").count(), 1);
+        assert_eq!(output.matches("this_is_b").count(), 1);
+    }
+}
+
 #[test]
 fn include_err() {
     match preprocess_into_string("#include", &mut DummyIncludeProvider, ()) {
-        Err(crate::PrepperError::ParseError { file: _, line: 1 }) => (),
+        Err(crate::PrepperError::ParseError {
+            file: _,
+            line: 1,
+            column: 9,
+            ..
+        }) => (),
         val => panic!("{:?}", val),
     }
 
     match preprocess_into_string("#include @", &mut DummyIncludeProvider, ()) {
-        Err(crate::PrepperError::ParseError { file: _, line: 1 }) => (),
+        Err(crate::PrepperError::ParseError {
+            file: _,
+            line: 1,
+            column: 10,
+            ..
+        }) => (),
         val => panic!("{:?}", val),
     }
 
     match preprocess_into_string("#include <foo", &mut DummyIncludeProvider, ()) {
-        Err(crate::PrepperError::ParseError { file: _, line: 1 }) => (),
+        Err(crate::PrepperError::ParseError {
+            file: _,
+            line: 1,
+            column: 14,
+            ..
+        }) => (),
+        val => panic!("{:?}", val),
+    }
+
+    match preprocess_into_string(
+        "// ü\nint a;\n  #include \"foo",
+        &mut DummyIncludeProvider,
+        (),
+    ) {
+        Err(crate::PrepperError::ParseError {
+            file: _,
+            line: 3,
+            column: 16,
+            byte_offset: 28,
+            ..
+        }) => (),
         val => panic!("{:?}", val),
     }
 
@@ -261,11 +425,513 @@ fn include_err() {
             file: fname,
             from: fsrc,
             from_line: 1,
-        }) if fname == "foo" && fsrc == "baz" => (),
+            cycle,
+            ..
+        }) if fname == "foo" && fsrc == "baz" && cycle == &vec!["foo", "bar", "baz", "foo"] => (),
+        val => panic!("{:?}", val),
+    }
+}
+
+fn test_string_with_options(s: &str, s2: &str, options: &crate::ProcessOptions) {
+    match preprocess_into_string_with_options(s, &mut DummyIncludeProvider, (), options) {
+        Ok(r) => assert_eq!(r, s2.to_string()),
+        val => panic!("{:?}", val),
+    };
+}
+
+#[test]
+fn conditional_passthrough() {
+    test_string(
+        "#ifdef FOO\n#include <foo>\n#else\n#include <bar>\n#endif",
+        "#ifdef FOO\n[foo]\n#else\n[bar]\n#endif",
+    );
+}
+
+#[test]
+fn conditional_include() {
+    let options = crate::ProcessOptions {
+        evaluate_conditionals: true,
+        ..Default::default()
+    };
+
+    test_string_with_options(
+        "#ifdef FOO\n#include <foo>\n#else\n#include <bar>\n#endif",
+        "#ifdef FOO\n\n#else\n[bar]\n#endif",
+        &options,
+    );
+    test_string_with_options(
+        "#define FOO\n#ifdef FOO\n#include <foo>\n#else\n#include <bar>\n#endif",
+        "#define FOO\n#ifdef FOO\n[foo]\n#else\n\n#endif",
+        &options,
+    );
+    test_string_with_options(
+        "#define A 2\n#if A * 2 == 4 && !defined(B)\n#include <foo>\n#elif 1\n#include <bar>\n#endif",
+        "#define A 2\n#if A * 2 == 4 && !defined(B)\n[foo]\n#elif 1\n\n#endif",
+        &options,
+    );
+    test_string_with_options(
+        "#if 0\n#if 1/0\n#include <foo>\n#endif\n#elif 1 || 1/0\n#include <bar>\n#endif",
+        "#if 0\n#if 1/0\n\n#endif\n#elif 1 || 1/0\n[bar]\n#endif",
+        &options,
+    );
+    test_string_with_options(
+        "#define FOO\n#undef FOO\n#ifndef FOO\n#include <foo>\n#endif",
+        "#define FOO\n#undef FOO\n#ifndef FOO\n[foo]\n#endif",
+        &options,
+    );
+    test_string_with_options(
+        "#define V(x) (x + 1)\n#define W V\n#if V(1) == 2 && W(0)\n#include <foo>\n#endif",
+        "#define V(x) (x + 1)\n#define W V\n#if V(1) == 2 && W(0)\n[foo]\n#endif",
+        &options,
+    );
+
+    // Guarded includes are never requested from the provider.
+    let mut include_provider = HashMapIncludeProvider(
+        [("bar", "int bar;")]
+            .iter()
+            .map(|(a, b)| (a.to_string(), b.to_string()))
+            .collect(),
+    );
+
+    assert_eq!(
+        preprocess_into_string_with_options(
+            "#ifdef USE_SHADOWS\n#include <shadows>\n#endif\n#include <bar>",
+            &mut include_provider,
+            (),
+            &options,
+        )
+        .unwrap(),
+        "#ifdef USE_SHADOWS\n\n#endif\nint bar;"
+    );
+}
+
+#[test]
+fn strip_inactive_code() {
+    let options = crate::ProcessOptions {
+        evaluate_conditionals: true,
+        strip_inactive_code: true,
+        ..Default::default()
+    };
+
+    test_string_with_options(
+        "#ifdef FOO\nint foo;\n#if 1\nint bar;\n#endif\n#else\nint baz;\n#endif",
+        "#ifdef FOO\n\n\n\n\n#else\nint baz;\n#endif",
+        &options,
+    );
+}
+
+#[test]
+fn conditional_err() {
+    let options = crate::ProcessOptions {
+        evaluate_conditionals: true,
+        ..Default::default()
+    };
+
+    for (source, err_line) in [
+        ("#endif", 1),
+        ("#if 1\n#else\n#else\n#endif", 3),
+        ("int foo;\n#ifdef FOO", 2),
+        ("#if (1\n#endif", 1),
+        ("#if 1/0\n#endif", 1),
+        ("#ifdef\n#endif", 1),
+    ]
+    .iter()
+    {
+        match preprocess_into_string_with_options(source, &mut DummyIncludeProvider, (), &options) {
+            Err(crate::PrepperError::DirectiveError { line, .. }) if line == *err_line => (),
+            val => panic!("{:?}: {:?}", source, val),
+        }
+    }
+
+    // Too deep to evaluate without overflowing the stack
+    for nested in ["(".repeat(5000), "!".repeat(5000), "-".repeat(5000)] {
+        let source = format!("int foo;\n#if {}1\n#endif", nested);
+        match preprocess_into_string_with_options(&source, &mut DummyIncludeProvider, (), &options)
+        {
+            Err(crate::PrepperError::DirectiveError { line: 2, .. }) => (),
+            val => panic!("{:?}: {:?}", nested, val),
+        }
+    }
+}
+
+#[test]
+fn defines_prelude() {
+    let mut include_provider = HashMapIncludeProvider(
+        [
+            (
+                "foo",
+                "#if QUALITY > 1\n#include <bar>\n#endif\nvoid main();",
+            ),
+            ("bar", "int bar;"),
+        ]
+        .iter()
+        .map(|(a, b)| (a.to_string(), b.to_string()))
+        .collect(),
+    );
+
+    let options = crate::ProcessOptions {
+        evaluate_conditionals: true,
+        defines: vec![
+            ("QUALITY".to_string(), "2".to_string()),
+            ("USE_SHADOWS".to_string(), String::new()),
+        ],
+        ..Default::default()
+    };
+
+    assert_eq!(
+        crate::process_file_with_options("foo", &mut include_provider, (), &options).unwrap(),
+        vec![
+            crate::SourceChunk {
+                file: crate::DEFINES_PRELUDE_FILE.to_string(),
+                line_offset: 0,
+                column_offset: 0,
+                source: "#define QUALITY 2\n#define USE_SHADOWS\n".to_string(),
+                context: (),
+            },
+            crate::SourceChunk {
+                file: "foo".to_string(),
+                line_offset: 0,
+                column_offset: 0,
+                source: "#if QUALITY > 1\n".to_string(),
+                context: (),
+            },
+            crate::SourceChunk {
+                file: "bar".to_string(),
+                line_offset: 0,
+                column_offset: 0,
+                source: "int bar;".to_string(),
+                context: (),
+            },
+            crate::SourceChunk {
+                file: "foo".to_string(),
+                line_offset: 1,
+                column_offset: 14,
+                source: "\n#endif\nvoid main();".to_string(),
+                context: (),
+            },
+        ]
+    );
+
+    let options = crate::ProcessOptions {
+        defines: vec![("1NVALID".to_string(), String::new())],
+        ..Default::default()
+    };
+
+    assert!(crate::process_file_with_options("foo", &mut include_provider, (), &options).is_err());
+}
+
+#[cfg(feature = "macros")]
+#[test]
+fn macro_expansion() {
+    let options = crate::ProcessOptions {
+        expand_macros: true,
+        ..Default::default()
+    };
+
+    test_string_with_options("#define N 4\nfloat a[N];", "\nfloat a[4];", &options);
+    test_string_with_options(
+        "#define SQR(x) ((x) * (x))\nfloat b = SQR(a + 1);",
+        "\nfloat b = ((a + 1) * (a + 1));",
+        &options,
+    );
+    test_string_with_options(
+        "#define STR(x) #x\n#define CAT(a, b) a ## b\nCAT(foo, 2) STR(a \"b\")",
+        "\n\nfoo2 \"a \\\"b\\\"\"",
+        &options,
+    );
+    test_string_with_options(
+        "#define F(...) f(__VA_ARGS__)\nF(1, 2) F()",
+        "\nf(1, 2) f()",
+        &options,
+    );
+
+    // Self-referential macros are not expanded recursively.
+    test_string_with_options(
+        "#define foo foo + bar\n#define bar foo\nfoo; bar;",
+        "\n\nfoo + foo; foo + bar;",
+        &options,
+    );
+
+    // Rescanning picks up function-like macros named by an expansion.
+    test_string_with_options(
+        "#define f(x) x + 1\n#define g f\ng(2)",
+        "\n\n2 + 1",
+        &options,
+    );
+
+    // Newlines swallowed by a multi-line invocation are re-emitted after it.
+    test_string_with_options(
+        "#define F(a, b) a + b\nF(1,\n2)\nint x;",
+        "\n1 + 2\n\nint x;",
+        &options,
+    );
+
+    // Conditionals are evaluated and removed, including via function-like macros.
+    test_string_with_options(
+        "#define IS_ON(x) (x == 1)\n#define Q 1\n#if IS_ON(Q)\nint on;\n#else\nint off;\n#endif\n#undef Q\nQ",
+        "\n\n\nint on;\n\n\n\n\nQ",
+        &options,
+    );
+
+    // Unrecognized directives are copied verbatim.
+    test_string_with_options(
+        "#define version 1\n#version 450\nversion",
+        "\n#version 450\n1",
+        &options,
+    );
+
+    // Defines are applied directly instead of being emitted as a prelude.
+    let mut include_provider = HashMapIncludeProvider(
+        [
+            ("foo", "#include <bar>\nint x = BAR;"),
+            ("bar", "#define BAR QUALITY"),
+        ]
+        .iter()
+        .map(|(a, b)| (a.to_string(), b.to_string()))
+        .collect(),
+    );
+
+    let chunks = crate::process_file_with_options(
+        "foo",
+        &mut include_provider,
+        (),
+        &crate::ProcessOptions {
+            defines: vec![("QUALITY".to_string(), "2".to_string())],
+            ..options.clone()
+        },
+    )
+    .unwrap();
+    assert_eq!(
+        chunks.into_iter().map(|c| c.source).collect::<String>(),
+        "\nint x = 2;"
+    );
+
+    match preprocess_into_string_with_options(
+        "#define F(a) a\n\nF(1, 2)",
+        &mut DummyIncludeProvider,
+        (),
+        &options,
+    ) {
+        Err(crate::PrepperError::MacroError { line: 3, .. }) => (),
+        val => panic!("{:?}", val),
+    }
+}
+
+#[test]
+fn error_include_stack() {
+    let mut include_provider = HashMapIncludeProvider(
+        [
+            ("a", "int a;\n#include <b>"),
+            ("b", "\n\n#include <c>"),
+            ("c", "int c;\n#include @"),
+        ]
+        .iter()
+        .map(|(a, b)| (a.to_string(), b.to_string()))
+        .collect(),
+    );
+
+    let err = crate::process_file_with_options(
+        "a",
+        &mut include_provider,
+        (),
+        &crate::ProcessOptions::default(),
+    )
+    .unwrap_err();
+
+    assert_eq!(
+        err.include_stack()
+            .iter()
+            .map(|e| (e.file.as_str(), e.line))
+            .collect::<Vec<_>>(),
+        vec![("a", 2), ("b", 3), ("c", 2)]
+    );
+    assert!(matches!(
+        err,
+        crate::PrepperError::ParseError { line: 2, .. }
+    ));
+    assert!(err.to_string().ends_with("a:2 -> b:3 -> c:2"));
+}
+
+/// Resolves includes relative to the directory of the including file
+struct RelativePathIncludeProvider(HashMap<String, String>);
+impl crate::IncludeProvider for RelativePathIncludeProvider {
+    type IncludeContext = String;
+
+    fn resolve_path(
+        &self,
+        path: &str,
+        _kind: crate::IncludeKind,
+        _includer: Option<&ResolvedIncludePath>,
+        context: &Self::IncludeContext,
+    ) -> Result<crate::ResolvedInclude<Self::IncludeContext>, crate::BoxedIncludeProviderError>
+    {
+        let mut components: Vec<&str> = context.split('/').filter(|c| !c.is_empty()).collect();
+        for component in path.split('/') {
+            match component {
+                ".." => {
+                    components.pop();
+                }
+                "." => {}
+                _ => components.push(component),
+            }
+        }
+
+        let resolved = components.join("/");
+        let dir = components[..components.len() - 1].join("/");
+
+        Ok(crate::ResolvedInclude {
+            resolved_path: crate::ResolvedIncludePath(resolved),
+            context: dir,
+        })
+    }
+
+    fn get_include(
+        &mut self,
+        resolved: &ResolvedIncludePath,
+    ) -> Result<String, crate::BoxedIncludeProviderError> {
+        Ok(self.0.get(&resolved.0).unwrap().clone())
+    }
+}
+
+#[test]
+fn include_kind_and_includer() {
+    struct RecordingIncludeProvider(std::cell::RefCell<Vec<String>>);
+    impl crate::IncludeProvider for RecordingIncludeProvider {
+        type IncludeContext = ();
+
+        fn resolve_path(
+            &self,
+            path: &str,
+            kind: crate::IncludeKind,
+            includer: Option<&ResolvedIncludePath>,
+            _context: &Self::IncludeContext,
+        ) -> Result<crate::ResolvedInclude<Self::IncludeContext>, crate::BoxedIncludeProviderError>
+        {
+            self.0.borrow_mut().push(format!(
+                "{:?} {:?} from {:?}",
+                path,
+                kind,
+                includer.map(|i| &i.0)
+            ));
+
+            Ok(crate::ResolvedInclude {
+                resolved_path: crate::ResolvedIncludePath(path.to_owned()),
+                context: (),
+            })
+        }
+
+        fn get_include(
+            &mut self,
+            resolved: &ResolvedIncludePath,
+        ) -> Result<String, crate::BoxedIncludeProviderError> {
+            Ok(match resolved.0.as_str() {
+                "main" => "#include \"local\"\n#include <system>",
+                _ => "",
+            }
+            .to_string())
+        }
+    }
+
+    let mut include_provider = RecordingIncludeProvider(Default::default());
+    crate::process_file("main", &mut include_provider, ()).unwrap();
+
+    assert_eq!(
+        include_provider.0.into_inner(),
+        vec![
+            "\"main\" Quoted from None",
+            "\"local\" Quoted from Some(\"main\")",
+            "\"system\" AngleBracket from Some(\"main\")",
+        ]
+    );
+}
+
+#[test]
+fn recursion_by_resolved_path() {
+    let mut include_provider = RelativePathIncludeProvider(
+        [
+            ("main", "#include \"x/a\"\n#include \"y/a\""),
+            ("x/a", "#include \"b\""),
+            ("x/b", "int x_b;"),
+            ("y/a", "#include \"../z/c\""),
+            ("z/c", "\n#include \"./../y/a\""),
+        ]
+        .iter()
+        .map(|(a, b)| (a.to_string(), b.to_string()))
+        .collect(),
+    );
+
+    // `x/a` and `y/a` share the relative name `a` without forming a cycle,
+    // while `../y/a` from `z` is the same file as `y/a`.
+    match crate::process_file_with_options(
+        "main",
+        &mut include_provider,
+        String::new(),
+        &crate::ProcessOptions::default(),
+    ) {
+        Err(crate::PrepperError::RecursiveInclude {
+            file,
+            from,
+            from_line: 2,
+            cycle,
+            ..
+        }) => {
+            assert_eq!(file, "y/a");
+            assert_eq!(from, "z/c");
+            assert_eq!(cycle, vec!["y/a", "z/c", "y/a"]);
+        }
         val => panic!("{:?}", val),
     }
 }
 
+#[test]
+fn source_map() {
+    let mut include_provider = HashMapIncludeProvider(
+        [("foo", "a\n#include <bar>\nc"), ("bar", "b  b")]
+            .iter()
+            .map(|(a, b)| (a.to_string(), b.to_string()))
+            .collect(),
+    );
+
+    let chunks = crate::process_file("foo", &mut include_provider, ()).unwrap();
+    let source_map = crate::SourceMap::from_chunks(&chunks);
+
+    let location = |file: &str, line, column| crate::SourceLocation {
+        file: file.to_string(),
+        line,
+        column,
+    };
+
+    // Generated code: "a\nb  b\nc"
+    assert_eq!(
+        source_map.original_location(0, 0),
+        Some(location("foo", 0, 0))
+    );
+    assert_eq!(
+        source_map.original_location(1, 3),
+        Some(location("bar", 0, 3))
+    );
+    assert_eq!(
+        source_map.original_location(2, 0),
+        Some(location("foo", 2, 0))
+    );
+    assert_eq!(source_map.original_location(3, 0), None);
+
+    assert_eq!(
+        source_map.generated_location(&location("bar", 0, 2)),
+        Some((1, 2))
+    );
+    assert_eq!(
+        source_map.generated_location(&location("foo", 2, 0)),
+        Some((2, 0))
+    );
+    assert_eq!(source_map.generated_location(&location("baz", 0, 0)), None);
+
+    assert_eq!(
+        source_map.to_json(Some("out.glsl")),
+        r#"{"version":3,"file":"out.glsl","sources":["foo","bar"],"names":[],"mappings":"AAAA;ACAA,IDCc;AACd"}"#
+    );
+}
+
 struct FileIncludeProvider;
 impl crate::IncludeProvider for FileIncludeProvider {
     type IncludeContext = ();
@@ -273,6 +939,8 @@ impl crate::IncludeProvider for FileIncludeProvider {
     fn resolve_path(
         &self,
         path: &str,
+        _kind: crate::IncludeKind,
+        _includer: Option<&ResolvedIncludePath>,
         _context: &Self::IncludeContext,
     ) -> Result<crate::ResolvedInclude<Self::IncludeContext>, crate::BoxedIncludeProviderError>
     {
@@ -294,3 +962,1153 @@ impl crate::IncludeProvider for FileIncludeProvider {
 fn include_file() {
     assert!(preprocess_into_string("src/lib.rs", &mut FileIncludeProvider, ()).is_ok());
 }
+
+/// Creates a fresh directory with the given files, for tests which need a real filesystem
+fn create_test_files(test_name: &str, files: &[(&str, &str)]) -> std::path::PathBuf {
+    let root = std::env::temp_dir().join(format!(
+        "shader-prepper-{}-{}",
+        test_name,
+        std::process::id()
+    ));
+    let _ = std::fs::remove_dir_all(&root);
+
+    for (path, contents) in files {
+        let path = root.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+
+    root
+}
+
+#[test]
+fn fs_include_provider() {
+    let root = create_test_files(
+        "fs_include_provider",
+        &[
+            (
+                "shaders/main.glsl",
+                "#include \"common/a.glsl\"\n#include \"lib.glsl\"\n#include <common/b.glsl>",
+            ),
+            ("shaders/common/a.glsl", "#include \"b.glsl\"\n"),
+            ("shaders/common/b.glsl", "int b;"),
+            ("include/lib.glsl", "int lib;"),
+            ("include/common/b.glsl", "int include_b;"),
+        ],
+    );
+
+    let mut include_provider = crate::FsIncludeProvider::new(vec![root.join("include")]);
+    let chunks = crate::process_file(
+        root.join("shaders/./common/../main.glsl").to_str().unwrap(),
+        &mut include_provider,
+        std::path::PathBuf::new(),
+    )
+    .unwrap();
+
+    assert_eq!(
+        chunks.iter().map(|c| c.source.as_str()).collect::<String>(),
+        "int b;\n\nint lib;\nint include_b;"
+    );
+    assert_eq!(
+        std::path::Path::new(&chunks[0].file),
+        root.join("shaders/common/b.glsl")
+    );
+    assert_eq!(chunks[0].context, root.join("shaders/common"));
+
+    assert!(crate::process_file(
+        root.join("shaders/missing.glsl").to_str().unwrap(),
+        &mut include_provider,
+        std::path::PathBuf::new(),
+    )
+    .is_err());
+
+    let _ = std::fs::remove_dir_all(root);
+}
+
+#[test]
+fn hlsl_include_provider() {
+    let root = create_test_files(
+        "hlsl_include_provider",
+        &[
+            (
+                "shaders/main.hlsl",
+                "#include \"sub/a.hlsl\"\n#include <lib.hlsl>",
+            ),
+            ("shaders/sub/a.hlsl", "#include \"inner/b.hlsl\"\n"),
+            (
+                "shaders/sub/inner/b.hlsl",
+                "#include \"common.hlsl\"\n#include \"shared.hlsl\"\n",
+            ),
+            ("shaders/common.hlsl", "int common;\n"),
+            ("shaders/shared.hlsl", "int root_shared;\n"),
+            ("shaders/sub/shared.hlsl", "int sub_shared;\n"),
+            ("include/lib.hlsl", "int lib;"),
+            ("include/common.hlsl", "int include_common;"),
+        ],
+    );
+
+    let mut include_provider = crate::HlslIncludeProvider::new(vec![root.join("include")]);
+    let options = crate::ProcessOptions {
+        dialect: crate::Dialect::Hlsl,
+        ..Default::default()
+    };
+    let chunks = crate::process_file_with_options(
+        root.join("shaders/main.hlsl").to_str().unwrap(),
+        &mut include_provider,
+        vec![std::path::PathBuf::new()],
+        &options,
+    )
+    .unwrap();
+
+    assert_eq!(
+        chunks.iter().map(|c| c.source.as_str()).collect::<String>(),
+        "int common;\n\nint sub_shared;\n\n\n\nint lib;"
+    );
+    assert_eq!(
+        chunks[0].context,
+        vec![
+            root.join("shaders"),
+            root.join("shaders/sub/inner"),
+            root.join("shaders/sub"),
+            root.join("shaders"),
+            std::path::PathBuf::new(),
+        ]
+    );
+
+    let _ = std::fs::remove_dir_all(root);
+}
+
+#[test]
+fn hlsl_dialect() {
+    let mut include_provider = HashMapIncludeProvider(
+        [
+            ("foo", "#include \"dir\\bar.hlsl\"\nfloat4 main();"),
+            ("dir\\bar.hlsl", "int bar;"),
+        ]
+        .iter()
+        .map(|(a, b)| (a.to_string(), b.to_string()))
+        .collect(),
+    );
+
+    let options = crate::ProcessOptions {
+        dialect: crate::Dialect::Hlsl,
+        ..Default::default()
+    };
+    let chunks =
+        crate::process_file_with_options("foo", &mut include_provider, (), &options).unwrap();
+
+    assert_eq!(
+        crate::to_string_with_line_directives(&chunks, crate::LineDirectiveStyle::Hlsl),
+        "#line 1 \"dir\\\\bar.hlsl\"\nint bar;\n#line 1 \"foo\"\n\nfloat4 main();"
+    );
+}
+
+#[test]
+fn depfile() {
+    let mut include_provider = HashMapIncludeProvider(
+        [
+            (
+                "main",
+                "#include <once>\n#include <my lib>\n#include <once>",
+            ),
+            ("once", "#pragma once\nint once;"),
+            ("my lib", "#include <once>\nint lib;"),
+        ]
+        .iter()
+        .map(|(a, b)| (a.to_string(), b.to_string()))
+        .collect(),
+    );
+
+    let output = crate::process_file_with_output(
+        "main",
+        &mut include_provider,
+        (),
+        &crate::ProcessOptions::default(),
+    )
+    .unwrap();
+
+    assert_eq!(
+        output
+            .dependencies
+            .iter()
+            .map(|d| d.0.as_str())
+            .collect::<Vec<_>>(),
+        vec!["main", "once", "my lib"]
+    );
+    assert_eq!(
+        output.to_depfile("out/main.spv"),
+        "out/main.spv: \\\n  main \\\n  once \\\n  my\\ lib\n"
+    );
+}
+
+#[test]
+fn include_graph() {
+    let mut include_provider = HashMapIncludeProvider(
+        [
+            ("main", "#include <a>\n\n#include <b>"),
+            ("a", "#pragma once\n#include <b>"),
+            ("b", "#include <a>\nint b;"),
+        ]
+        .iter()
+        .map(|(a, b)| (a.to_string(), b.to_string()))
+        .collect(),
+    );
+
+    let output = crate::process_file_with_output(
+        "main",
+        &mut include_provider,
+        (),
+        &crate::ProcessOptions::default(),
+    )
+    .unwrap();
+
+    let graph = &output.include_graph;
+    assert_eq!(graph.files, output.dependencies);
+    assert_eq!(
+        graph
+            .edges
+            .iter()
+            .map(|e| (
+                graph.files[e.from].0.as_str(),
+                graph.files[e.to].0.as_str(),
+                e.line
+            ))
+            .collect::<Vec<_>>(),
+        vec![
+            ("main", "a", 1),
+            ("a", "b", 2),
+            ("b", "a", 1),
+            ("main", "b", 3),
+            ("b", "a", 1),
+        ]
+    );
+
+    assert_eq!(
+        graph.to_dot(),
+        "digraph includes {\n    n0 [label=\"main\"];\n    n1 [label=\"a\"];\n    n2 [label=\"b\"];\n    \
+         n0 -> n1 [label=\"1\"];\n    n1 -> n2 [label=\"2\"];\n    n2 -> n1 [label=\"1\"];\n    \
+         n0 -> n2 [label=\"3\"];\n    n2 -> n1 [label=\"1\"];\n}\n"
+    );
+    assert_eq!(
+        graph.to_json(),
+        r#"{"files":["main","a","b"],"edges":[{"from":0,"to":1,"line":1},{"from":1,"to":2,"line":2},{"from":2,"to":1,"line":1},{"from":0,"to":2,"line":3},{"from":2,"to":1,"line":1}]}"#
+    );
+}
+
+#[cfg(feature = "watch")]
+#[test]
+fn shader_watcher() {
+    let root = create_test_files(
+        "shader_watcher",
+        &[
+            ("a.glsl", "#include \"common.glsl\"\nint a;"),
+            ("b.glsl", "int b;"),
+            ("common.glsl", "int common;"),
+        ],
+    );
+
+    let mut watcher = crate::ShaderWatcher::new(crate::FsIncludeProvider::new(Vec::<
+        std::path::PathBuf,
+    >::new()))
+    .unwrap();
+
+    for file in &["a.glsl", "b.glsl"] {
+        watcher
+            .process_file(
+                root.join(file).to_str().unwrap(),
+                std::path::PathBuf::new(),
+                &crate::ProcessOptions::default(),
+            )
+            .unwrap();
+    }
+    assert!(watcher.changed_roots().is_empty());
+
+    std::fs::write(root.join("common.glsl"), "int common2;").unwrap();
+
+    let mut changed_roots = Vec::new();
+    for _ in 0..100 {
+        changed_roots = watcher.changed_roots();
+        if !changed_roots.is_empty() {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+    assert_eq!(
+        changed_roots,
+        vec![root.join("a.glsl").to_str().unwrap().to_string()]
+    );
+
+    let _ = std::fs::remove_dir_all(root);
+}
+
+#[test]
+fn caching_include_provider() {
+    let root = create_test_files(
+        "caching_include_provider",
+        &[
+            ("a.glsl", "#include \"common.glsl\"\nint a;"),
+            ("b.glsl", "#include \"common.glsl\"\nint b;"),
+            ("common.glsl", "int common;"),
+        ],
+    );
+
+    let mut include_provider = crate::CachingIncludeProvider::new(crate::FsIncludeProvider::new(
+        Vec::<std::path::PathBuf>::new(),
+    ));
+    let process = |include_provider: &mut crate::CachingIncludeProvider<_>, file: &str| {
+        crate::process_file(
+            root.join(file).to_str().unwrap(),
+            include_provider,
+            std::path::PathBuf::new(),
+        )
+        .unwrap()
+        .iter()
+        .map(|c| c.source.clone())
+        .collect::<String>()
+    };
+
+    assert_eq!(
+        process(&mut include_provider, "a.glsl"),
+        "int common;\nint a;"
+    );
+
+    let common = crate::ResolvedIncludePath(root.join("common.glsl").to_str().unwrap().to_owned());
+    let hash = include_provider.content_hash(&common).unwrap();
+
+    // Served from the cache despite the change on disk
+    std::fs::write(root.join("common.glsl"), "int common2;").unwrap();
+    assert_eq!(
+        process(&mut include_provider, "b.glsl"),
+        "int common;\nint b;"
+    );
+    assert_eq!(include_provider.content_hash(&common), Some(hash));
+
+    include_provider.invalidate(&common);
+    assert_eq!(include_provider.content_hash(&common), None);
+    assert_eq!(
+        process(&mut include_provider, "b.glsl"),
+        "int common2;\nint b;"
+    );
+    assert_ne!(include_provider.content_hash(&common), Some(hash));
+
+    let _ = std::fs::remove_dir_all(root);
+}
+
+#[test]
+fn preprocessor_session() {
+    let mut include_provider = HashMapIncludeProvider(
+        [
+            ("a", "int a;\n#include <common>\n#include <guarded>"),
+            (
+                "b",
+                "#include <guarded> int b;\n#include <common>\n#include <once>",
+            ),
+            ("c", "#include <once>\n#include <once>\n#include <guarded>"),
+            ("common", "#include <once>\nint common;"),
+            ("once", "#pragma once\nint once;"),
+            ("guarded", "#ifndef G\n#define G\n#include <once>\n#endif"),
+            (
+                "perm",
+                "#ifdef FEATURE\n#include <once> /* c */\nfeature\n#else\nno_feature\n#endif\n",
+            ),
+            ("p1", "#define FEATURE\n#include <perm>\n#include <guarded>"),
+            ("p2", "#include <perm>\n#include <common>"),
+            (
+                "wrapper",
+                "#ifndef W\n#define W\n#ifdef A\n#include <guarded>\n#endif\n#undef G\n#endif",
+            ),
+            (
+                "p3",
+                "#include <wrapper>\n#include <guarded>\n#include <wrapper>\n#include <guarded>",
+            ),
+        ]
+        .iter()
+        .map(|(a, b)| (a.to_string(), b.to_string()))
+        .collect(),
+    );
+
+    for (evaluate_conditionals, strip_inactive_code) in
+        [(false, false), (true, false), (true, true)]
+    {
+        let options = crate::ProcessOptions {
+            evaluate_conditionals,
+            strip_inactive_code,
+            ..Default::default()
+        };
+        let mut preprocessor = crate::Preprocessor::new(options.clone());
+
+        // Each root twice, so that every scan gets replayed, with different defines for `perm`
+        for root in [
+            "a", "b", "c", "p1", "p2", "p3", "a", "b", "c", "p1", "p2", "p3",
+        ] {
+            let expected =
+                crate::process_file_with_output(root, &mut include_provider, (), &options).unwrap();
+            let output = preprocessor
+                .process_file(root, &mut include_provider, ())
+                .unwrap();
+
+            assert_eq!(output.chunks, expected.chunks);
+            assert_eq!(output.include_graph, expected.include_graph);
+        }
+    }
+}
+
+#[test]
+fn line_directives() {
+    let mut include_provider = HashMapIncludeProvider(
+        [
+            (
+                "foo",
+                "  \n#version 450\n#include <bar> int x;\nvoid main();",
+            ),
+            ("bar", "int bar;"),
+        ]
+        .iter()
+        .map(|(a, b)| (a.to_string(), b.to_string()))
+        .collect(),
+    );
+
+    let chunks = crate::process_file("foo", &mut include_provider, ()).unwrap();
+
+    assert_eq!(
+        crate::to_string_with_line_directives(&chunks, crate::LineDirectiveStyle::Line),
+        "  \n#version 450\n#line 1\nint bar;\n#line 3\n int x;\nvoid main();"
+    );
+    assert_eq!(
+        crate::to_string_with_line_directives(&chunks, crate::LineDirectiveStyle::LineAndFile),
+        "  \n#version 450\n#extension GL_GOOGLE_cpp_style_line_directive : enable\n\
+         #line 1 \"bar\"\nint bar;\n#line 3 \"foo\"\n int x;\nvoid main();"
+    );
+
+    let chunks = crate::process_file("bar", &mut include_provider, ()).unwrap();
+    assert_eq!(
+        crate::to_string_with_line_directives(&chunks, crate::LineDirectiveStyle::Line),
+        "#line 1\nint bar;"
+    );
+}
+
+#[test]
+fn version_and_extensions_first() {
+    let mut include_provider = HashMapIncludeProvider(
+        [
+            ("foo", "// foo\n#version 450\n#include <bar>\nvoid main();"),
+            (
+                "bar",
+                "int bar;\n  #extension GL_EXT_foo : require\nint baz;",
+            ),
+        ]
+        .iter()
+        .map(|(a, b)| (a.to_string(), b.to_string()))
+        .collect(),
+    );
+
+    let options = crate::ProcessOptions {
+        defines: vec![("FOO".to_string(), "1".to_string())],
+        hoist_extensions: true,
+        ..Default::default()
+    };
+
+    let chunks =
+        crate::process_file_with_options("foo", &mut include_provider, (), &options).unwrap();
+
+    assert_eq!(
+        chunks
+            .iter()
+            .map(|c| (c.file.as_str(), c.line_offset, c.source.as_str()))
+            .collect::<Vec<_>>(),
+        vec![
+            ("foo", 0, "\n#version 450\n"),
+            ("bar", 1, "  #extension GL_EXT_foo : require\n"),
+            (crate::DEFINES_PRELUDE_FILE, 0, "#define FOO 1\n"),
+            ("bar", 0, "int bar;\n\nint baz;"),
+            ("foo", 2, "\nvoid main();"),
+        ]
+    );
+
+    // Conditional extensions stay where they are.
+    let mut include_provider = HashMapIncludeProvider(
+        [(
+            "es",
+            "#version 100\n#ifdef GL_ES\n#extension GL_OES_standard_derivatives : require\n\
+             #endif\n#extension GL_EXT_bar : enable\nvoid main();",
+        )]
+        .iter()
+        .map(|(a, b)| (a.to_string(), b.to_string()))
+        .collect(),
+    );
+
+    let options = crate::ProcessOptions {
+        hoist_extensions: true,
+        ..Default::default()
+    };
+
+    let chunks =
+        crate::process_file_with_options("es", &mut include_provider, (), &options).unwrap();
+
+    assert_eq!(
+        chunks.iter().map(|c| c.source.as_str()).collect::<String>(),
+        "#version 100\n#extension GL_EXT_bar : enable\n\
+         #ifdef GL_ES\n#extension GL_OES_standard_derivatives : require\n#endif\n\nvoid main();"
+    );
+
+    // Nor do ones continuing another line, e.g. within a macro.
+    let mut include_provider = HashMapIncludeProvider(
+        [
+            (
+                "continued",
+                "#version 450\n#define M \\\n#extension GL_EXT_foo : enable\nvoid main();",
+            ),
+            ("version_only", "#version 450"),
+        ]
+        .iter()
+        .map(|(a, b)| (a.to_string(), b.to_string()))
+        .collect(),
+    );
+
+    let chunks =
+        crate::process_file_with_options("continued", &mut include_provider, (), &options).unwrap();
+    assert_eq!(
+        chunks.iter().map(|c| c.source.as_str()).collect::<String>(),
+        "#version 450\n#define M \\\n#extension GL_EXT_foo : enable\nvoid main();"
+    );
+
+    // The preamble starts on a new line, even if the `#version` directive doesn't end with one.
+    let options = crate::ProcessOptions {
+        defines: vec![("A".to_string(), "1".to_string())],
+        ..Default::default()
+    };
+
+    let chunks =
+        crate::process_file_with_options("version_only", &mut include_provider, (), &options)
+            .unwrap();
+    assert_eq!(
+        chunks.iter().map(|c| c.source.as_str()).collect::<String>(),
+        "#version 450\n#define A 1\n"
+    );
+}
+
+#[cfg(feature = "gl_compiler")]
+#[test]
+fn gl_compiler_diagnostics() {
+    use crate::gl_compiler::{compile_shader, Diagnostic, Severity, ShaderCompilerOutput};
+
+    let mut include_provider = HashMapIncludeProvider(
+        [
+            ("foo", "int foo;\n#include <bar>\nvoid main();"),
+            ("bar", "int bar;"),
+        ]
+        .iter()
+        .map(|(a, b)| (a.to_string(), b.to_string()))
+        .collect(),
+    );
+    let chunks = crate::process_file("foo", &mut include_provider, ()).unwrap();
+
+    let compile = |log: &str| {
+        compile_shader(chunks.iter(), |sources| {
+            assert_eq!(
+                sources,
+                [
+                    "int foo;\n",
+                    "#line 0 2\n",
+                    "int bar;",
+                    "\n#line 0 3\n",
+                    "\nvoid main();"
+                ]
+            );
+
+            ShaderCompilerOutput {
+                artifact: (),
+                log: Some(log.to_owned()),
+            }
+        })
+    };
+
+    let output = compile("ERROR: 2:0: 'bar' : redefinition\nERROR: 1 compilation errors.\n");
+    assert_eq!(
+        output.log.as_deref(),
+        Some("ERROR: bar(1): 'bar' : redefinition\nERROR: 1 compilation errors.\n")
+    );
+    assert_eq!(
+        output.diagnostics,
+        vec![Diagnostic {
+            severity: Severity::Error,
+            file: Some("bar".to_string()),
+            line: Some(1),
+            column: None,
+            message: "'bar' : redefinition".to_string(),
+        }]
+    );
+
+    let output = compile("3(1) : warning C7022: unrecognized profile specifier\r\n");
+    assert_eq!(
+        output.log.as_deref(),
+        Some("foo(3) : warning C7022: unrecognized profile specifier\r\n")
+    );
+    assert_eq!(
+        output.diagnostics,
+        vec![Diagnostic {
+            severity: Severity::Warning,
+            file: Some("foo".to_string()),
+            line: Some(3),
+            column: None,
+            message: "C7022: unrecognized profile specifier".to_string(),
+        }]
+    );
+}
+
+#[cfg(feature = "gl_compiler")]
+#[test]
+fn gl_compiler_log_formats() {
+    use crate::gl_compiler::{compile_shader, Severity, ShaderCompilerOutput};
+
+    let mut include_provider = HashMapIncludeProvider(
+        [
+            ("foo", "int foo;\n#include <bar>\nvoid main();"),
+            ("bar", "int bar;"),
+        ]
+        .iter()
+        .map(|(a, b)| (a.to_string(), b.to_string()))
+        .collect(),
+    );
+    let chunks = crate::process_file("foo", &mut include_provider, ()).unwrap();
+
+    // (severity, file, line, column, message)
+    type ExpectedDiagnostic = (
+        Severity,
+        &'static str,
+        Option<usize>,
+        Option<usize>,
+        &'static str,
+    );
+
+    // (driver, log, remapped log, diagnostics)
+    let fixtures: &[(&str, &str, &str, &[ExpectedDiagnostic])] = &[
+        (
+            "AMD",
+            "ERROR: 2:0: 'bar' : redefinition\nWARNING: 3:1: 'main' : no return\n",
+            "ERROR: bar(1): 'bar' : redefinition\nWARNING: foo(3): 'main' : no return\n",
+            &[
+                (Severity::Error, "bar", Some(1), None, "'bar' : redefinition"),
+                (Severity::Warning, "foo", Some(3), None, "'main' : no return"),
+            ],
+        ),
+        (
+            "Apple",
+            "ERROR: 0:1: Use of undeclared identifier 'baz'\nERROR: 0:1: Invalid call\n",
+            "ERROR: foo(1): Use of undeclared identifier 'baz'\nERROR: foo(1): Invalid call\n",
+            &[
+                (Severity::Error, "foo", Some(1), None, "Use of undeclared identifier 'baz'"),
+                (Severity::Error, "foo", Some(1), None, "Invalid call"),
+            ],
+        ),
+        (
+            "ANGLE",
+            "ERROR: 2:0: 'bar' : redefinition\nERROR: 3:1:7: 'main' : syntax error\n",
+            "ERROR: bar(1): 'bar' : redefinition\nERROR: foo(3,7): 'main' : syntax error\n",
+            &[
+                (Severity::Error, "bar", Some(1), None, "'bar' : redefinition"),
+                (Severity::Error, "foo", Some(3), Some(7), "'main' : syntax error"),
+            ],
+        ),
+        (
+            "NVIDIA",
+            "2(0) : error C1038: declaration of \"bar\" conflicts\n3(1) : warning C7050: unused\n",
+            "bar(1) : error C1038: declaration of \"bar\" conflicts\nfoo(3) : warning C7050: unused\n",
+            &[
+                (Severity::Error, "bar", Some(1), None, "C1038: declaration of \"bar\" conflicts"),
+                (Severity::Warning, "foo", Some(3), None, "C7050: unused"),
+            ],
+        ),
+        (
+            "Mesa",
+            "2:0(5): error: `bar' redeclared\n3:1(1): preprocessor warning: extra tokens\n",
+            "bar(1,5): error: `bar' redeclared\nfoo(3,1): preprocessor warning: extra tokens\n",
+            &[
+                (Severity::Error, "bar", Some(1), Some(5), "`bar' redeclared"),
+                (Severity::Warning, "foo", Some(3), Some(1), "extra tokens"),
+            ],
+        ),
+        (
+            "Mali",
+            "2:0: L0003: Redefinition of 'bar'\n3:1: W0001: Unused variable\n",
+            "bar(1): L0003: Redefinition of 'bar'\nfoo(3): W0001: Unused variable\n",
+            &[
+                (Severity::Error, "bar", Some(1), None, "L0003: Redefinition of 'bar'"),
+                (Severity::Warning, "foo", Some(3), None, "W0001: Unused variable"),
+            ],
+        ),
+        (
+            "Adreno",
+            "ERROR: 2:0: 'bar' : redefinition\nERROR: 1 compilation errors.  No code generated.\n",
+            "ERROR: bar(1): 'bar' : redefinition\nERROR: 1 compilation errors.  No code generated.\n",
+            &[(Severity::Error, "bar", Some(1), None, "'bar' : redefinition")],
+        ),
+    ];
+
+    for (driver, log, remapped_log, diagnostics) in fixtures {
+        let output = compile_shader(chunks.iter(), |_| ShaderCompilerOutput {
+            artifact: (),
+            log: Some(log.to_string()),
+        });
+
+        assert_eq!(output.log.as_deref(), Some(*remapped_log), "{}", driver);
+        assert_eq!(
+            output
+                .diagnostics
+                .iter()
+                .map(|d| (
+                    d.severity,
+                    d.file.as_deref().unwrap(),
+                    d.line,
+                    d.column,
+                    d.message.as_str()
+                ))
+                .collect::<Vec<_>>(),
+            diagnostics.to_vec(),
+            "{}",
+            driver
+        );
+    }
+}
+
+#[cfg(feature = "gl_compiler")]
+#[test]
+fn gl_compiler_unmapped_locations() {
+    use crate::gl_compiler::{compile_shader, ShaderCompilerOutput};
+
+    let mut include_provider = HashMapIncludeProvider(
+        [("foo", "\n#include <bar>\nint foo;"), ("bar", "int bar;")]
+            .iter()
+            .map(|(a, b)| (a.to_string(), b.to_string()))
+            .collect(),
+    );
+    let chunks = crate::process_file("foo", &mut include_provider, ()).unwrap();
+
+    let log = format!(
+        "ERROR: 7:3: bad string\n3({}) : error C0000: bad line\n\
+         ERROR: 0:99999999999999999999999: too big\nERROR: 0:1: fine\n",
+        usize::MAX
+    );
+    let output = compile_shader(chunks.iter(), |_| ShaderCompilerOutput {
+        artifact: (),
+        log: Some(log.clone()),
+    });
+
+    assert_eq!(
+        output.log.unwrap(),
+        format!(
+            "ERROR: 7:3 (unmapped): bad string\n3({}) (unmapped) : error C0000: bad line\n\
+             ERROR: 0:99999999999999999999999 (unmapped): too big\nERROR: foo(1): fine\n",
+            usize::MAX
+        )
+    );
+    assert_eq!(
+        output
+            .diagnostics
+            .iter()
+            .map(|d| (d.file.as_deref(), d.line))
+            .collect::<Vec<_>>(),
+        vec![
+            (None, Some(3)),
+            (None, Some(usize::MAX)),
+            (None, None),
+            (Some("foo"), Some(1))
+        ]
+    );
+}
+
+#[cfg(feature = "gl_compiler")]
+#[test]
+fn gl_compiler_fallible_callback() {
+    use crate::gl_compiler::{try_compile_shader, ShaderCompilerOutput};
+
+    let mut include_provider = HashMapIncludeProvider(
+        [("foo", "int foo;")]
+            .iter()
+            .map(|(a, b)| (a.to_string(), b.to_string()))
+            .collect(),
+    );
+    let chunks = crate::process_file("foo", &mut include_provider, ()).unwrap();
+
+    let mut compilations = 0;
+    let mut compile = |fail: bool| {
+        try_compile_shader(chunks.iter(), |sources| {
+            compilations += 1;
+
+            if fail {
+                Err("context lost")
+            } else {
+                Ok(ShaderCompilerOutput {
+                    artifact: sources.concat(),
+                    log: None,
+                })
+            }
+        })
+    };
+
+    assert_eq!(compile(false).unwrap().artifact, "int foo;");
+    assert_eq!(compile(true).err(), Some("context lost"));
+    assert_eq!(compilations, 2);
+}
+
+/// (severity, file, line, column)
+type ExpectedDiagnostic = (
+    crate::Severity,
+    Option<&'static str>,
+    Option<usize>,
+    Option<usize>,
+);
+
+/// Checks `remap` against fixtures of (log, remapped log, diagnostics), and that
+/// the first diagnostic of `message_log` has the message `message`.
+fn check_log_remapping(
+    remap: impl Fn(&str) -> crate::RemappedLog,
+    fixtures: &[(&str, &str, &[ExpectedDiagnostic])],
+    (message_log, message): (&str, &str),
+) {
+    for (log, remapped_log, diagnostics) in fixtures {
+        let remapped = remap(log);
+
+        assert_eq!(remapped.log, *remapped_log);
+        assert_eq!(
+            remapped
+                .diagnostics
+                .iter()
+                .map(|d| (d.severity, d.file.as_deref(), d.line, d.column))
+                .collect::<Vec<_>>(),
+            diagnostics.to_vec()
+        );
+    }
+
+    assert_eq!(remap(message_log).diagnostics[0].message, message);
+}
+
+#[test]
+fn glslang_log() {
+    use crate::glslang_log::remap_glslang_log;
+    use crate::Severity;
+
+    let mut include_provider = HashMapIncludeProvider(
+        [
+            (
+                "main.frag",
+                "#version 450\n#include <lib.glsl>\nvoid main() { x; }",
+            ),
+            ("lib.glsl", "float a;\nfloat b = c;\n"),
+        ]
+        .iter()
+        .map(|(a, b)| (a.to_string(), b.to_string()))
+        .collect(),
+    );
+    let chunks = crate::process_file("main.frag", &mut include_provider, ()).unwrap();
+    let source_map = crate::SourceMap::from_chunks(&chunks);
+
+    // Generated code: "#version 450\nfloat a;\nfloat b = c;\n\nvoid main() { x; }"
+
+    // (log, remapped log, diagnostics)
+    let fixtures: &[(&str, &str, &[ExpectedDiagnostic])] = &[
+        (
+            // glslangValidator
+            "out.frag\n\
+             ERROR: out.frag:3: 'c' : undeclared identifier\n\
+             ERROR: out.frag:5:15: 'x' : undeclared identifier\n\
+             WARNING: other.glsl:1: unrelated\n\
+             ERROR: out.frag:50: past the end\n\
+             ERROR: 3 compilation errors.  No code generated.\n",
+            "out.frag\n\
+             ERROR: lib.glsl:2: 'c' : undeclared identifier\n\
+             ERROR: main.frag:3:15: 'x' : undeclared identifier\n\
+             WARNING: other.glsl:1: unrelated\n\
+             ERROR: out.frag:50 (unmapped): past the end\n\
+             ERROR: 3 compilation errors.  No code generated.\n",
+            &[
+                (Severity::Error, Some("lib.glsl"), Some(2), None),
+                (Severity::Error, Some("main.frag"), Some(3), Some(15)),
+                (Severity::Error, None, Some(50), None),
+            ],
+        ),
+        (
+            // glslc
+            "out.frag:3: error: 'c' : undeclared identifier\r\n\
+             out.frag:2: warning: 'a' : unused\r\n\
+             2 errors generated.\r\n",
+            "lib.glsl:2: error: 'c' : undeclared identifier\r\n\
+             lib.glsl:1: warning: 'a' : unused\r\n\
+             2 errors generated.\r\n",
+            &[
+                (Severity::Error, Some("lib.glsl"), Some(2), None),
+                (Severity::Warning, Some("lib.glsl"), Some(1), None),
+            ],
+        ),
+    ];
+
+    check_log_remapping(
+        |log| remap_glslang_log(log, "out.frag", &source_map),
+        fixtures,
+        (
+            "out.frag:3: error: 'c' : undeclared identifier",
+            "'c' : undeclared identifier",
+        ),
+    );
+}
+
+#[test]
+fn dxc_log() {
+    use crate::hlsl_log::remap_dxc_log;
+    use crate::Severity;
+
+    let mut include_provider = HashMapIncludeProvider(
+        [
+            (
+                "main.hlsl",
+                "#include \"lib.hlsl\"\nfloat4 main() : SV_Target { x; }",
+            ),
+            ("lib.hlsl", "float a;\nfloat b = c;\n"),
+        ]
+        .iter()
+        .map(|(a, b)| (a.to_string(), b.to_string()))
+        .collect(),
+    );
+    let chunks = crate::process_file("main.hlsl", &mut include_provider, ()).unwrap();
+    let source_map = crate::SourceMap::from_chunks(&chunks);
+
+    // Generated code: "float a;\nfloat b = c;\n\nfloat4 main() : SV_Target { x; }"
+
+    // (log, remapped log, diagnostics)
+    let fixtures: &[(&str, &str, &[ExpectedDiagnostic])] = &[
+        (
+            // FXC
+            "out.hlsl(2,11-11): error X3004: undeclared identifier 'c'\n\
+             out.hlsl(4,29): error X3004: undeclared identifier 'x'\n\
+             out.hlsl(1): warning X3206: unused\n\
+             out.hlsl(40,1): error X3000: past the end\n\
+             compilation failed; no code produced\n",
+            "lib.hlsl(2,11-11): error X3004: undeclared identifier 'c'\n\
+             main.hlsl(2,29): error X3004: undeclared identifier 'x'\n\
+             lib.hlsl(1): warning X3206: unused\n\
+             out.hlsl(40,1) (unmapped): error X3000: past the end\n\
+             compilation failed; no code produced\n",
+            &[
+                (Severity::Error, Some("lib.hlsl"), Some(2), Some(11)),
+                (Severity::Error, Some("main.hlsl"), Some(2), Some(29)),
+                (Severity::Warning, Some("lib.hlsl"), Some(1), None),
+                (Severity::Error, None, Some(40), Some(1)),
+            ],
+        ),
+        (
+            // DXC
+            "out.hlsl:4:29: error: use of undeclared identifier 'x'\n\
+             float4 main() : SV_Target { x; }\n\
+             \x20                           ^\n\
+             other.hlsl:1:1: note: unrelated\n",
+            "main.hlsl:2:29: error: use of undeclared identifier 'x'\n\
+             float4 main() : SV_Target { x; }\n\
+             \x20                           ^\n\
+             other.hlsl:1:1: note: unrelated\n",
+            &[(Severity::Error, Some("main.hlsl"), Some(2), Some(29))],
+        ),
+    ];
+
+    check_log_remapping(
+        |log| remap_dxc_log(log, "out.hlsl", &source_map),
+        fixtures,
+        ("out.hlsl(2,11): error X3004: 'c'", "X3004: 'c'"),
+    );
+}
+
+#[test]
+fn wgsl_dialect() {
+    let mut include_provider = HashMapIncludeProvider(
+        [
+            (
+                "main.wgsl",
+                "/* a /* nested */ #include \"missing\" */\n#import \"dir\\lib.wgsl\"\nfn main() {}",
+            ),
+            ("dir\\lib.wgsl", "const x = 1; /* */\n"),
+        ]
+        .iter()
+        .map(|(a, b)| (a.to_string(), b.to_string()))
+        .collect(),
+    );
+
+    let options = crate::ProcessOptions {
+        dialect: crate::Dialect::Wgsl,
+        ..Default::default()
+    };
+    let chunks =
+        crate::process_file_with_options("main.wgsl", &mut include_provider, (), &options).unwrap();
+
+    assert_eq!(
+        chunks.iter().map(|c| c.source.as_str()).collect::<String>(),
+        format!("{}\nconst x = 1;      \n\nfn main() {{}}", " ".repeat(39))
+    );
+    assert_eq!(chunks[1].file, "dir\\lib.wgsl");
+    assert_eq!(chunks[2].line_offset, 1);
+}
+
+#[test]
+fn wgsl_log() {
+    use crate::wgsl_log::remap_wgsl_log;
+    use crate::Severity;
+
+    let mut include_provider = HashMapIncludeProvider(
+        [
+            ("main.wgsl", "#import \"lib.wgsl\"\nfn main() { x; }"),
+            ("lib.wgsl", "const a = 1;\nconst b = c;\n"),
+        ]
+        .iter()
+        .map(|(a, b)| (a.to_string(), b.to_string()))
+        .collect(),
+    );
+    let options = crate::ProcessOptions {
+        dialect: crate::Dialect::Wgsl,
+        ..Default::default()
+    };
+    let chunks =
+        crate::process_file_with_options("main.wgsl", &mut include_provider, (), &options).unwrap();
+    let source_map = crate::SourceMap::from_chunks(&chunks);
+
+    // Generated code: "const a = 1;\nconst b = c;\n\nfn main() { x; }"
+    // (log, remapped log, diagnostics)
+    let fixtures: &[(&str, &str, &[ExpectedDiagnostic])] = &[
+        (
+            // naga
+            "error: no definition in scope for identifier: `c`\n\
+             \x20 ┌─ out.wgsl:2:11\n\
+             \x20 │\n\
+             2 │ const b = c;\n\
+             \x20 │           ^ unknown identifier\n\
+             \n\
+             error: past the end\n\
+             \x20 ┌─ out.wgsl:40:1\n",
+            "error: no definition in scope for identifier: `c`\n\
+             \x20 ┌─ lib.wgsl:2:11\n\
+             \x20 │\n\
+             2 │ const b = c;\n\
+             \x20 │           ^ unknown identifier\n\
+             \n\
+             error: past the end\n\
+             \x20 ┌─ out.wgsl:40:1 (unmapped)\n",
+            &[
+                (Severity::Error, Some("lib.wgsl"), Some(2), Some(11)),
+                (Severity::Error, None, Some(40), Some(1)),
+            ],
+        ),
+        (
+            // Tint
+            "out.wgsl:4:13 error: unresolved value 'x'\n\
+             fn main() { x; }\n\
+             \x20           ^\n\
+             other.wgsl:1:1 note: unrelated\n",
+            "main.wgsl:2:13 error: unresolved value 'x'\n\
+             fn main() { x; }\n\
+             \x20           ^\n\
+             other.wgsl:1:1 note: unrelated\n",
+            &[(Severity::Error, Some("main.wgsl"), Some(2), Some(13))],
+        ),
+    ];
+
+    check_log_remapping(
+        |log| remap_wgsl_log(log, "out.wgsl", &source_map),
+        fixtures,
+        ("out.wgsl:2:11 error: unknown 'c'", "unknown 'c'"),
+    );
+}
+
+#[test]
+fn module_imports() {
+    let mut include_provider = HashMapIncludeProvider(
+        [
+            (
+                "main.wgsl",
+                "#import shapes::circle\n\
+                 #import util::{square, Pair}\n\
+                 fn main() -> f32 { let p: Pair = Pair(1.0, 2.0); \
+                 return circle::area(p.a) + square(p.b); }",
+            ),
+            (
+                "shapes/circle.wgsl",
+                "#define_import_path shapes::circle\n\
+                 #import util::{square}\n\
+                 const PI: f32 = 3.14;\n\
+                 fn area(r: f32) -> f32 { return PI * square(r); }",
+            ),
+            (
+                "util.wgsl",
+                "struct Pair { a: f32, b: f32 }\nfn square(x: f32) -> f32 { return x * x; }\n",
+            ),
+        ]
+        .iter()
+        .map(|(a, b)| (a.to_string(), b.to_string()))
+        .collect(),
+    );
+
+    let options = crate::ProcessOptions {
+        dialect: crate::Dialect::Wgsl,
+        module_imports: true,
+        ..Default::default()
+    };
+    let chunks =
+        crate::process_file_with_options("main.wgsl", &mut include_provider, (), &options).unwrap();
+
+    assert_eq!(
+        chunks.iter().map(|c| c.source.as_str()).collect::<String>(),
+        "\n\
+         struct util__Pair { a: f32, b: f32 }\n\
+         fn util__square(x: f32) -> f32 { return x * x; }\n\
+         \n\
+         const shapes_circle__PI: f32 = 3.14;\n\
+         fn shapes_circle__area(r: f32) -> f32 { return shapes_circle__PI * util__square(r); }\n\
+         \n\
+         fn main() -> f32 { let p: util__Pair = util__Pair(1.0, 2.0); \
+         return shapes_circle__area(p.a) + util__square(p.b); }"
+    );
+
+    // Interface declarations, such as uniform blocks and stage inputs, keep their names.
+    let mut include_provider = HashMapIncludeProvider(
+        [
+            (
+                "main.glsl",
+                "#version 450\n#import lighting\nvoid main() { lighting::shade(lighting::ambient); }",
+            ),
+            (
+                "lighting.glsl",
+                "layout(binding = 0) uniform Light { vec3 dir; } light;\n\
+                 in vec3 normal;\n\
+                 const float ambient = min(0.1, 1.0), ambient2 = 0.2;\n\
+                 float shade(float a) { return a + light.dir.x * normal.x; }\n",
+            ),
+            ("bad.glsl", "#import lighting::{shade\n"),
+        ]
+        .iter()
+        .map(|(a, b)| (a.to_string(), b.to_string()))
+        .collect(),
+    );
+
+    let options = crate::ProcessOptions {
+        module_imports: true,
+        ..Default::default()
+    };
+    let chunks =
+        crate::process_file_with_options("main.glsl", &mut include_provider, (), &options).unwrap();
+    assert_eq!(
+        chunks.iter().map(|c| c.source.as_str()).collect::<String>(),
+        "#version 450\n\
+         layout(binding = 0) uniform Light { vec3 dir; } light;\n\
+         in vec3 normal;\n\
+         const float lighting__ambient = min(0.1, 1.0), lighting__ambient2 = 0.2;\n\
+         float lighting__shade(float a) { return a + light.dir.x * normal.x; }\n\
+         \nvoid main() { lighting__shade(lighting__ambient); }"
+    );
+
+    match crate::process_file_with_output("bad.glsl", &mut include_provider, (), &options) {
+        Err(crate::PrepperError::DirectiveError { line, message, .. }) => {
+            assert_eq!((line, message.as_str()), (1, "malformed #import"));
+        }
+        _ => panic!("expected a directive error"),
+    }
+
+    // Without the option, the directives are left for the compiler.
+    let chunks = crate::process_file("bad.glsl", &mut include_provider, ()).unwrap();
+    assert_eq!(chunks[0].source, "#import lighting::{shade\n");
+}
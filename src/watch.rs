@@ -0,0 +1,215 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::Duration;
+
+use notify::event::{AccessKind, AccessMode};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+
+use crate::{
+    BoxedIncludeProviderError, IncludeKind, IncludeProvider, PrepperError, ProcessOptions,
+    ProcessOutput, ResolvedInclude, ResolvedIncludePath,
+};
+
+/// Interval at which files are checked when native filesystem notifications are unavailable
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Tracks the files read while processing root shaders, and reports the roots which need
+/// processing again when any of those files change.
+///
+/// Wraps an `IncludeProvider` whose `ResolvedIncludePath`s are filesystem paths,
+/// such as [`FsIncludeProvider`](crate::FsIncludeProvider). Changes are detected using
+/// the platform's native notifications (e.g. inotify), falling back to polling where those
+/// aren't available. The directories containing the files are watched, rather than
+/// the files themselves, so that editors saving by replacing files are handled.
+pub struct ShaderWatcher<P> {
+    include_provider: P,
+
+    /// Files read while processing each root, keyed by the path passed to `process_file`
+    roots: HashMap<String, HashSet<PathBuf>>,
+
+    watched_dirs: HashSet<PathBuf>,
+    watcher: Box<dyn Watcher + Send>,
+    polling: bool,
+    event_tx: Sender<notify::Result<Event>>,
+    event_rx: Receiver<notify::Result<Event>>,
+}
+
+impl<P> ShaderWatcher<P>
+where
+    P: IncludeProvider,
+    P::IncludeContext: Clone,
+{
+    pub fn new(include_provider: P) -> notify::Result<Self> {
+        let (event_tx, event_rx) = channel();
+
+        let (watcher, polling) = match notify::recommended_watcher(event_tx.clone()) {
+            Ok(watcher) => (Box::new(watcher) as Box<dyn Watcher + Send>, false),
+            Err(_) => (Self::poll_watcher(event_tx.clone())?, true),
+        };
+
+        Ok(Self {
+            include_provider,
+            roots: HashMap::new(),
+            watched_dirs: HashSet::new(),
+            watcher,
+            polling,
+            event_tx,
+            event_rx,
+        })
+    }
+
+    pub fn include_provider(&mut self) -> &mut P {
+        &mut self.include_provider
+    }
+
+    /// Like [`process_file_with_output`](crate::process_file_with_output), additionally
+    /// watching every file whose contents were requested from the `IncludeProvider`
+    /// in the process, including `file_path` itself.
+    ///
+    /// The files are watched even if processing fails, so that fixing the error
+    /// gets `file_path` reported by [`changed_roots`](Self::changed_roots).
+    /// Includes which fail to resolve, e.g. because `FsIncludeProvider` finds no such file,
+    /// aren't watched though: creating the missing file doesn't get `file_path` reported.
+    pub fn process_file(
+        &mut self,
+        file_path: &str,
+        include_context: P::IncludeContext,
+        options: &ProcessOptions,
+    ) -> Result<ProcessOutput<P::IncludeContext>, PrepperError> {
+        let mut recording = RecordingIncludeProvider {
+            inner: &mut self.include_provider,
+            read: Vec::new(),
+        };
+
+        let result =
+            crate::process_file_with_output(file_path, &mut recording, include_context, options);
+
+        let files: HashSet<PathBuf> = recording
+            .read
+            .iter()
+            .map(|path| watch_key(Path::new(&path.0)))
+            .collect();
+
+        for file in &files {
+            if let Some(dir) = file.parent() {
+                self.watch_dir(dir);
+            }
+        }
+
+        self.roots.insert(file_path.to_owned(), files);
+        result
+    }
+
+    /// Stop reporting changes affecting `file_path`.
+    pub fn unwatch_root(&mut self, file_path: &str) {
+        self.roots.remove(file_path);
+    }
+
+    /// Roots passed to [`process_file`](Self::process_file) which depend on any file changed
+    /// since the last call. Doesn't block.
+    pub fn changed_roots(&mut self) -> Vec<String> {
+        let mut changed_files: HashSet<PathBuf> = HashSet::new();
+
+        for event in self.event_rx.try_iter().flatten() {
+            let is_change = match event.kind {
+                EventKind::Access(AccessKind::Close(AccessMode::Write)) => true,
+                EventKind::Access(_) => false,
+                _ => true,
+            };
+
+            if is_change {
+                changed_files.extend(event.paths);
+            }
+        }
+
+        let mut changed_roots: Vec<String> = self
+            .roots
+            .iter()
+            .filter(|(_, files)| !files.is_disjoint(&changed_files))
+            .map(|(root, _)| root.clone())
+            .collect();
+        changed_roots.sort();
+        changed_roots
+    }
+
+    fn watch_dir(&mut self, dir: &Path) {
+        if self.watched_dirs.contains(dir) {
+            return;
+        }
+
+        if self
+            .watcher
+            .watch(dir, RecursiveMode::NonRecursive)
+            .is_err()
+            && !self.polling
+        {
+            // E.g. out of inotify watches. Switch to polling everything.
+            if let Ok(watcher) = Self::poll_watcher(self.event_tx.clone()) {
+                self.watcher = watcher;
+                self.polling = true;
+
+                for dir in &self.watched_dirs {
+                    let _ = self.watcher.watch(dir, RecursiveMode::NonRecursive);
+                }
+                let _ = self.watcher.watch(dir, RecursiveMode::NonRecursive);
+            }
+        }
+
+        self.watched_dirs.insert(dir.to_owned());
+    }
+
+    fn poll_watcher(
+        event_tx: Sender<notify::Result<Event>>,
+    ) -> notify::Result<Box<dyn Watcher + Send>> {
+        let config = notify::Config::default().with_poll_interval(POLL_INTERVAL);
+        Ok(Box::new(notify::PollWatcher::new(event_tx, config)?))
+    }
+}
+
+/// Path under which change notifications for `path` arrive: that of its canonicalized
+/// parent directory, joined with the file name.
+fn watch_key(path: &Path) -> PathBuf {
+    match (path.parent(), path.file_name()) {
+        (Some(dir), Some(name)) => {
+            let dir = if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir
+            };
+
+            dir.canonicalize()
+                .map(|dir| dir.join(name))
+                .unwrap_or_else(|_| path.to_owned())
+        }
+        _ => path.to_owned(),
+    }
+}
+
+/// Forwards to another provider, recording every file read
+struct RecordingIncludeProvider<'a, P> {
+    inner: &'a mut P,
+    read: Vec<ResolvedIncludePath>,
+}
+
+impl<'a, P: IncludeProvider> IncludeProvider for RecordingIncludeProvider<'a, P> {
+    type IncludeContext = P::IncludeContext;
+
+    fn resolve_path(
+        &self,
+        path: &str,
+        kind: IncludeKind,
+        includer: Option<&ResolvedIncludePath>,
+        context: &Self::IncludeContext,
+    ) -> Result<ResolvedInclude<Self::IncludeContext>, BoxedIncludeProviderError> {
+        self.inner.resolve_path(path, kind, includer, context)
+    }
+
+    fn get_include(
+        &mut self,
+        path: &ResolvedIncludePath,
+    ) -> Result<String, BoxedIncludeProviderError> {
+        self.read.push(path.clone());
+        self.inner.get_include(path)
+    }
+}
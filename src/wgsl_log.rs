@@ -0,0 +1,71 @@
+//! Remapping of naga and Tint messages about shaders compiled as a single file, e.g. the
+//! concatenated sources of all `SourceChunk`s, back to the original files.
+//!
+//! Two message formats are recognized:
+//!
+//! * `file.wgsl:12:5 error: message` (Tint),
+//! * `error: message` followed by `┌─ file.wgsl:12:5` (naga, as rendered by codespan).
+//!
+//! Only locations in the compiled file, as named by `file_name`, are remapped; messages
+//! about other files are left as they are. The line numbers in the gutter of source snippets
+//! printed by naga still refer to the compiled file.
+
+pub use crate::log_remap::RemappedLog;
+
+use crate::log_remap::{
+    colon_location, parse_colon_location, parse_severity, remap_lines, remap_location,
+};
+use crate::{Severity, SourceMap};
+
+/// Remap locations in `log`, referring to lines of the compiled file `file_name`,
+/// using the `source_map` built from the chunks it was assembled from.
+///
+/// Locations which don't correspond to any chunk are left as they are,
+/// followed by `(unmapped)`, and produce diagnostics without a `file`.
+pub fn remap_wgsl_log(log: &str, file_name: &str, source_map: &SourceMap) -> RemappedLog {
+    const SEVERITIES: &[(&str, Severity)] = &[
+        ("error:", Severity::Error),
+        ("warning:", Severity::Warning),
+        ("note:", Severity::Info),
+        ("info:", Severity::Info),
+    ];
+
+    let mut diagnostics = Vec::new();
+
+    // Severity and message of the last naga message header, awaiting its location
+    let mut pending: Option<(Severity, &str)> = None;
+
+    let log = remap_lines(log, |line_str| {
+        if let Some(header) = parse_severity(line_str, SEVERITIES) {
+            pending = Some(header);
+            return line_str.to_owned();
+        }
+
+        let location_start = match line_str.find("┌─ ") {
+            Some(idx) => idx + "┌─ ".len(),
+            None => 0,
+        };
+
+        let location = match parse_colon_location(line_str, location_start, file_name) {
+            Some(location) => location,
+            None => return line_str.to_owned(),
+        };
+
+        let header = if location_start > 0 {
+            pending.take()
+        } else {
+            parse_severity(line_str[location.range.end..].trim_start(), SEVERITIES)
+        };
+
+        remap_location(
+            line_str,
+            &location,
+            header,
+            source_map,
+            &mut diagnostics,
+            |original| colon_location(original, location.column),
+        )
+    });
+
+    RemappedLog { log, diagnostics }
+}